@@ -0,0 +1,175 @@
+use crate::network::NetMessage;
+use macroquad::prelude::*;
+use std::collections::VecDeque;
+
+/// Message of the chat with the time it was received.
+struct ChatLine {
+    name: String,
+    text: String,
+    t: f64,
+}
+
+/// Text chat between the host, guests and spectators.
+///
+/// Messages are broadcast to every player of the room, including the sender,
+/// so a message shows up once the relay sent it back. Lines fade out after a
+/// while and are shown again while typing.
+pub struct Chat {
+    lines: VecDeque<ChatLine>,
+    /// Message being typed, `None` while the input box is closed.
+    input: Option<String>,
+}
+
+impl Chat {
+    const LINES: usize = 6;
+    /// Seconds a line is displayed, including its fading.
+    const DISPLAY_DURATION: f64 = 10.;
+    const FADE_DURATION: f64 = 2.;
+    pub const MAX_LEN: usize = 100;
+
+    pub fn new() -> Self {
+        Self {
+            lines: VecDeque::new(),
+            input: None,
+        }
+    }
+
+    /// Add a message received at `t`, messages longer than `MAX_LEN` are
+    /// truncated.
+    pub fn received(&mut self, name: String, text: &str, t: f64) {
+        let text: String = text.trim().chars().take(Chat::MAX_LEN).collect();
+        if text.is_empty() {
+            return;
+        }
+        log::info!("{}: {}", name, text);
+        self.lines.push_back(ChatLine { name, text, t });
+        if self.lines.len() > Chat::LINES {
+            self.lines.pop_front();
+        }
+    }
+
+    /// The keys typed go to the input box instead of the game.
+    pub fn is_typing(&self) -> bool {
+        self.input.is_some()
+    }
+
+    /// Open the input box with the T key, enter sends the message and escape
+    /// closes the box. Return the message to send.
+    pub fn update(&mut self, name: &str) -> Option<NetMessage> {
+        let input = match self.input.as_mut() {
+            Some(input) => input,
+            None => {
+                // Characters typed while playing are not part of the message.
+                while get_char_pressed().is_some() {}
+                if is_key_pressed(KeyCode::T) {
+                    self.input = Some(String::new());
+                }
+                return None;
+            }
+        };
+
+        while let Some(c) = get_char_pressed() {
+            if !c.is_control() && input.chars().count() < Chat::MAX_LEN {
+                input.push(c);
+            }
+        }
+        if is_key_pressed(KeyCode::Backspace) {
+            input.pop();
+        }
+        if is_key_pressed(KeyCode::Escape) {
+            self.input = None;
+        } else if is_key_pressed(KeyCode::Enter) {
+            let text = self.input.take().unwrap_or_default();
+            if !text.trim().is_empty() {
+                return Some(NetMessage::Chat {
+                    name: name.to_string(),
+                    text,
+                });
+            }
+        }
+        None
+    }
+
+    /// Opacity of a line received at `line_t`, fully visible while typing.
+    fn alpha(&self, line_t: f64, t: f64) -> f32 {
+        if self.is_typing() {
+            return 1.;
+        }
+        let remaining = Chat::DISPLAY_DURATION - (t - line_t);
+        (remaining / Chat::FADE_DURATION).clamp(0., 1.) as f32
+    }
+
+    /// Draw the lines still visible at `t` and the input box in the bottom
+    /// left corner of the screen.
+    pub fn draw(&self, t: f64) {
+        let font_size = 24.;
+        let mut y = screen_height() - 40.;
+        if let Some(input) = &self.input {
+            draw_rectangle(
+                0.,
+                y - 20.,
+                screen_width(),
+                28.,
+                Color::new(1., 1., 1., 0.6),
+            );
+            draw_text(&format!("> {}_", input), 10., y, font_size, BLACK);
+        }
+        for line in self.lines.iter().rev() {
+            y -= 24.;
+            let alpha = self.alpha(line.t, t);
+            if alpha > 0. {
+                draw_text(
+                    &format!("{}: {}", line.name, line.text),
+                    10.,
+                    y,
+                    font_size,
+                    Color {
+                        a: alpha,
+                        ..DARKGRAY
+                    },
+                );
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn chat_received_test() {
+        let mut chat = Chat::new();
+        chat.received(String::from("Uggla"), "  ", 1.);
+        assert!(chat.lines.is_empty());
+
+        chat.received(String::from("Uggla"), &"a".repeat(Chat::MAX_LEN + 10), 1.);
+        assert_eq!(chat.lines[0].text.len(), Chat::MAX_LEN);
+
+        for i in 0..Chat::LINES {
+            chat.received(String::from("Guest"), &i.to_string(), 2.);
+        }
+        assert_eq!(chat.lines.len(), Chat::LINES);
+        assert_eq!(chat.lines[0].text, "0");
+    }
+
+    #[test]
+    fn chat_fading_test() {
+        let mut chat = Chat::new();
+        assert_eq!(chat.alpha(1., 1.), 1.);
+        assert_eq!(
+            chat.alpha(1., 1. + Chat::DISPLAY_DURATION - Chat::FADE_DURATION),
+            1.
+        );
+        assert_eq!(
+            chat.alpha(1., 1. + Chat::DISPLAY_DURATION - Chat::FADE_DURATION / 2.),
+            0.5
+        );
+        assert_eq!(chat.alpha(1., 1. + Chat::DISPLAY_DURATION), 0.);
+        assert_eq!(chat.alpha(1., 100.), 0.);
+
+        // Old lines are shown again while typing.
+        chat.input = Some(String::new());
+        assert_eq!(chat.alpha(1., 100.), 1.);
+    }
+}
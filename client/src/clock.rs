@@ -0,0 +1,74 @@
+use planetoid_core::world::TICK;
+use std::sync::OnceLock;
+
+static START: OnceLock<f64> = OnceLock::new();
+
+/// Seconds since the game started, like `get_time` but also available
+/// without a window in server mode.
+pub fn now() -> f64 {
+    let now = macroquad::miniquad::date::now();
+    now - *START.get_or_init(|| now)
+}
+
+/// Accumulator of the time elapsed between frames, to run the simulation by
+/// fixed steps.
+///
+/// A 144 Hz window runs a step every 2 or 3 frames and a 30 Hz browser runs 2
+/// steps per frame, so every peer moves at the same speed.
+pub struct Ticker {
+    last_t: f64,
+    accumulated: f64,
+}
+
+impl Ticker {
+    /// Steps are dropped after a long frame, rather than freezing the game
+    /// to catch up.
+    const MAX_ELAPSED: f64 = 0.25;
+
+    pub fn new(t: f64) -> Self {
+        Self {
+            last_t: t,
+            accumulated: 0.,
+        }
+    }
+
+    /// Start again from `t`, the time elapsed meanwhile is not simulated.
+    pub fn reset(&mut self, t: f64) {
+        self.last_t = t;
+        self.accumulated = 0.;
+    }
+
+    /// Steps to run at `t`, the remaining time is kept for the next frame.
+    pub fn ticks(&mut self, t: f64) -> u32 {
+        self.accumulated += (t - self.last_t).clamp(0., Ticker::MAX_ELAPSED);
+        self.last_t = t;
+        let ticks = (self.accumulated / TICK).floor();
+        self.accumulated -= ticks * TICK;
+        ticks as u32
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ticker_test() {
+        // 144 Hz frames.
+        let mut ticker = Ticker::new(0.);
+        let ticks: u32 = (1..=144).map(|i| ticker.ticks(i as f64 / 144.)).sum();
+        assert!((59..=60).contains(&ticks));
+
+        // 30 Hz frames.
+        let mut ticker = Ticker::new(0.);
+        let ticks: Vec<u32> = (1..=30).map(|i| ticker.ticks(i as f64 / 30.)).collect();
+        assert!(ticks.iter().all(|ticks| *ticks >= 1));
+        assert!((59..=60).contains(&ticks.iter().sum::<u32>()));
+
+        // A frozen window does not run the whole time elapsed.
+        assert_eq!(ticker.ticks(11.), 15);
+        ticker.reset(20.);
+        assert_eq!(ticker.ticks(20.01), 0);
+        assert_eq!(ticker.ticks(20.02), 1);
+    }
+}
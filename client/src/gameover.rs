@@ -1,8 +1,9 @@
-use crate::asteroid::Asteroids;
-use crate::ship::Ship;
 use crate::sound::Sound;
 use crate::MAX_ASTEROIDS;
 use macroquad::prelude::*;
+use planetoid_core::asteroid::Asteroids;
+use planetoid_core::ship::Ship;
+use planetoid_core::world::World;
 
 #[allow(clippy::too_many_arguments)]
 pub fn manage_gameover(
@@ -10,10 +11,12 @@ pub fn manage_gameover(
     asteroids: &mut Asteroids,
     mode: &str,
     name: &str,
-    frame_count: &mut u32,
+    tick_count: &mut u32,
     gameover: &mut bool,
     gameover_msg_sent: &mut bool,
     sound: &mut Sound,
+    world: &mut World,
+    typing: bool,
 ) {
     // Take care this part is executed in a loop !
     // host is looping until the enter key is pressed
@@ -41,17 +44,18 @@ pub fn manage_gameover(
         DARKGRAY,
     );
 
-    if mode != "host" || is_key_down(KeyCode::Enter) {
+    // Enter sends the chat message being typed.
+    if mode != "host" || (!typing && is_key_pressed(KeyCode::Enter)) {
         log::info!("Restarting game.");
         players.clear();
-        players.push(Ship::new(String::from(name)));
+        players.push(Ship::new(String::from(name), world));
         *gameover = false;
         *gameover_msg_sent = false;
-        *asteroids = Asteroids::generate_field(String::from(name), 0);
+        *asteroids = Asteroids::default();
         sound.reset_played_sound();
         if mode == "host" {
-            *asteroids = Asteroids::generate_field(String::from(name), MAX_ASTEROIDS);
+            *asteroids = Asteroids::generate_field(String::from(name), MAX_ASTEROIDS, world);
         }
-        *frame_count = 0;
+        *tick_count = 0;
     }
 }
@@ -0,0 +1,329 @@
+use crate::network::NetMessage;
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+
+/// Version of the messages spoken by a peer and the optional features it
+/// supports.
+///
+/// The version changes when messages cannot be understood by the previous
+/// builds. New optional fields do not need a new version: they are declared
+/// with `#[serde(default)]` and older builds ignore the fields they do not
+/// know.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct Protocol {
+    /// 0 for the builds without handshake.
+    pub version: u32,
+    pub capabilities: Vec<String>,
+}
+
+impl Protocol {
+    /// 2 since the speeds are per second instead of per frame.
+    pub const VERSION: u32 = 2;
+    /// The host simulates the ships of the guests sending their inputs.
+    pub const INPUTS: &'static str = "inputs";
+    const CAPABILITIES: &'static [&'static str] = &[Protocol::INPUTS];
+
+    /// Protocol of this build.
+    pub fn current() -> Self {
+        Self {
+            version: Protocol::VERSION,
+            capabilities: Protocol::CAPABILITIES
+                .iter()
+                .map(|capability| capability.to_string())
+                .collect(),
+        }
+    }
+
+    /// Peers must speak the same version, capabilities are optional.
+    pub fn check(&self) -> Result<(), String> {
+        match self.version {
+            Protocol::VERSION => Ok(()),
+            0 => Err(format!(
+                "older build without protocol version, version {} expected",
+                Protocol::VERSION
+            )),
+            version => Err(format!(
+                "protocol version {}, version {} expected",
+                version,
+                Protocol::VERSION
+            )),
+        }
+    }
+
+    pub fn supports(&self, capability: &str) -> bool {
+        self.capabilities
+            .iter()
+            .any(|supported| supported == capability)
+    }
+}
+
+/// Versioned handshake of the guests and spectators joining the game.
+///
+/// A peer says hello with its protocol until the host welcomes it with its
+/// own protocol and the seed of its simulation, or rejects it with the
+/// reason. The host ignores the other messages of the peers it rejected.
+pub struct Handshake {
+    /// Seed of the simulation of the game, the one of the host once it
+    /// welcomed this peer.
+    seed: u64,
+    /// Protocol said to the host.
+    protocol: Protocol,
+    hello_t: Option<f64>,
+    /// Protocol of the host, once it welcomed this peer.
+    host_protocol: Option<Protocol>,
+    rejection: Option<String>,
+    /// The host was on the gameover screen when it welcomed this peer.
+    next_round: bool,
+    /// Answers of the host to the hellos received.
+    answers: Vec<NetMessage>,
+    rejected_peers: HashSet<String>,
+    /// Peers sending their whole ship instead of their inputs.
+    ship_state_peers: HashSet<String>,
+}
+
+impl Handshake {
+    /// The hello may be lost while the connection is established.
+    const HELLO_INTERVAL: f64 = 1.;
+
+    pub fn new(seed: u64) -> Self {
+        Self {
+            seed,
+            protocol: Protocol::current(),
+            hello_t: None,
+            host_protocol: None,
+            rejection: None,
+            next_round: false,
+            answers: Vec::new(),
+            rejected_peers: HashSet::new(),
+            ship_state_peers: HashSet::new(),
+        }
+    }
+
+    /// This peer sends its whole ship instead of its inputs, it does not
+    /// say the host it supports them.
+    pub fn send_ship_state(&mut self) {
+        self.protocol
+            .capabilities
+            .retain(|capability| capability != Protocol::INPUTS);
+    }
+
+    /// Say hello again, to join a new game or after reconnecting.
+    pub fn reset(&mut self) {
+        self.hello_t = None;
+        self.host_protocol = None;
+        self.rejection = None;
+        self.next_round = false;
+    }
+
+    /// Return the hello to send to the host at `t` if it is time to.
+    pub fn hello(&mut self, name: &str, spectator: bool, t: f64) -> Option<NetMessage> {
+        if self.welcomed()
+            || self.rejection.is_some()
+            || self
+                .hello_t
+                .is_some_and(|hello_t| t - hello_t < Handshake::HELLO_INTERVAL)
+        {
+            return None;
+        }
+        self.hello_t = Some(t);
+        Some(NetMessage::Hello {
+            name: name.to_string(),
+            protocol: self.protocol.clone(),
+            spectator,
+        })
+    }
+
+    /// Host side, answer the hello of `name`, which joins the next round on
+    /// `gameover`. Return false if the peer is rejected.
+    pub fn hello_received(&mut self, name: &str, protocol: &Protocol, gameover: bool) -> bool {
+        match protocol.check() {
+            Ok(()) => {
+                self.rejected_peers.remove(name);
+                if protocol.supports(Protocol::INPUTS) {
+                    self.ship_state_peers.remove(name);
+                } else {
+                    self.ship_state_peers.insert(name.to_string());
+                }
+                self.answers.push(NetMessage::Welcome {
+                    name: name.to_string(),
+                    protocol: Protocol::current(),
+                    gameover,
+                    seed: Some(self.seed),
+                });
+                true
+            }
+            Err(reason) => {
+                log::warn!("Rejecting {}: {}", name, reason);
+                self.rejected_peers.insert(name.to_string());
+                self.answers.push(NetMessage::Reject {
+                    name: name.to_string(),
+                    reason,
+                });
+                false
+            }
+        }
+    }
+
+    /// Host side, whether the messages of `name` are ignored.
+    pub fn is_rejected(&self, name: &str) -> bool {
+        self.rejected_peers.contains(name)
+    }
+
+    /// Host side, whether `name` said it sends its whole ship. The ship sent
+    /// by the other peers is ignored, the host simulates it from their inputs.
+    pub fn sends_ship_state(&self, name: &str) -> bool {
+        self.ship_state_peers.contains(name)
+    }
+
+    /// Host side, answers to the hellos received since the last call.
+    pub fn answers(&mut self) -> Vec<NetMessage> {
+        std::mem::take(&mut self.answers)
+    }
+
+    /// The host welcomed this peer, the peer checks the host speaks its
+    /// version too. Hosts without `seed` keep the seed of this peer.
+    pub fn welcome_received(&mut self, protocol: Protocol, gameover: bool, seed: Option<u64>) {
+        self.next_round = gameover;
+        if let Some(seed) = seed {
+            self.seed = seed;
+        }
+        match protocol.check() {
+            Ok(()) => self.host_protocol = Some(protocol),
+            Err(reason) => self.rejection = Some(format!("Incompatible host: {}", reason)),
+        }
+    }
+
+    pub fn reject_received(&mut self, reason: String) {
+        self.rejection = Some(format!("Rejected by the host: {}", reason));
+    }
+
+    /// The peer joined during a gameover and waits for the next round.
+    pub fn waits_next_round(&self) -> bool {
+        self.next_round
+    }
+
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    pub fn welcomed(&self) -> bool {
+        self.host_protocol.is_some()
+    }
+
+    /// Why this peer cannot join the game.
+    pub fn rejection(&self) -> Option<&str> {
+        self.rejection.as_deref()
+    }
+
+    pub fn host_supports(&self, capability: &str) -> bool {
+        self.host_protocol
+            .as_ref()
+            .is_some_and(|protocol| protocol.supports(capability))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn protocol_check_test() {
+        assert!(Protocol::current().check().is_ok());
+        assert!(Protocol::current().supports(Protocol::INPUTS));
+        assert!(Protocol::default().check().is_err());
+        let newer = Protocol {
+            version: Protocol::VERSION + 1,
+            capabilities: Vec::new(),
+        };
+        assert_eq!(
+            newer.check(),
+            Err(format!(
+                "protocol version {}, version {} expected",
+                Protocol::VERSION + 1,
+                Protocol::VERSION
+            ))
+        );
+    }
+
+    #[test]
+    fn handshake_hello_test() {
+        let mut handshake = Handshake::new(42);
+        assert!(matches!(
+            handshake.hello("Guest", false, 1.),
+            Some(NetMessage::Hello {
+                spectator: false,
+                ..
+            })
+        ));
+        assert!(handshake.hello("Guest", false, 1.5).is_none());
+        // Said again until the host answers.
+        assert!(handshake.hello("Guest", false, 2.).is_some());
+
+        handshake.welcome_received(Protocol::current(), false, Some(7));
+        assert!(handshake.welcomed());
+        assert!(handshake.host_supports(Protocol::INPUTS));
+        assert_eq!(handshake.seed(), 7);
+        assert!(!handshake.waits_next_round());
+        assert!(handshake.hello("Guest", false, 5.).is_none());
+
+        handshake.reset();
+        assert!(!handshake.welcomed());
+        assert!(handshake.hello("Guest", false, 5.).is_some());
+
+        // Joining on the gameover screen of a host without seed.
+        handshake.welcome_received(Protocol::current(), true, None);
+        assert_eq!(handshake.seed(), 7);
+        assert!(handshake.waits_next_round());
+        handshake.reset();
+        assert!(!handshake.waits_next_round());
+    }
+
+    #[test]
+    fn handshake_host_test() {
+        let mut host = Handshake::new(42);
+        assert!(host.hello_received("Guest", &Protocol::current(), true));
+        assert!(!host.hello_received("Old", &Protocol::default(), false));
+        assert!(!host.is_rejected("Guest"));
+        assert!(host.is_rejected("Old"));
+        assert!(!host.sends_ship_state("Guest"));
+
+        // A guest sending its ship says it does not send its inputs.
+        let mut guest = Handshake::new(42);
+        guest.send_ship_state();
+        let protocol = match guest.hello("Legacy", false, 1.) {
+            Some(NetMessage::Hello { protocol, .. }) => protocol,
+            msg => panic!("Unexpected message {:?}", msg),
+        };
+        assert!(host.hello_received("Legacy", &protocol, false));
+        assert!(host.sends_ship_state("Legacy"));
+
+        let answers = host.answers();
+        assert!(matches!(
+            &answers[0],
+            NetMessage::Welcome { name, gameover: true, seed: Some(42), .. } if name == "Guest"
+        ));
+        assert!(matches!(&answers[1], NetMessage::Reject { name, .. } if name == "Old"));
+        assert_eq!(answers.len(), 3);
+        assert!(host.answers().is_empty());
+    }
+
+    #[test]
+    fn handshake_rejected_test() {
+        let mut handshake = Handshake::new(42);
+        handshake.reject_received(String::from("old build"));
+        assert_eq!(
+            handshake.rejection(),
+            Some("Rejected by the host: old build")
+        );
+        assert!(handshake.hello("Guest", false, 1.).is_none());
+
+        // An incompatible host is refused by the peer too.
+        let mut handshake = Handshake::new(42);
+        handshake.welcome_received(Protocol::default(), false, None);
+        assert!(!handshake.welcomed());
+        assert!(handshake
+            .rejection()
+            .unwrap()
+            .starts_with("Incompatible host"));
+    }
+}
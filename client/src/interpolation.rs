@@ -0,0 +1,225 @@
+use crate::screen;
+use macroquad::prelude::Vec2;
+use planetoid_core::asteroid::Asteroids;
+use planetoid_core::collision::Collided;
+use planetoid_core::ship::Ship;
+use std::collections::{HashMap, VecDeque};
+
+/// Position and rotation of an entity.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Pose {
+    pub pos: Vec2,
+    pub rot: f32,
+}
+
+impl Pose {
+    /// Interpolate from `self` to `other`, through the edges of the screen
+    /// when it is the shortest way.
+    fn lerp(&self, other: &Pose, s: f32) -> Pose {
+        let mut delta = other.pos - self.pos;
+        if delta.x > screen::WIDTH / 2. {
+            delta.x -= screen::WIDTH;
+        } else if delta.x < -screen::WIDTH / 2. {
+            delta.x += screen::WIDTH;
+        }
+        if delta.y > screen::HEIGHT / 2. {
+            delta.y -= screen::HEIGHT;
+        } else if delta.y < -screen::HEIGHT / 2. {
+            delta.y += screen::HEIGHT;
+        }
+        let pos = self.pos + delta * s;
+        Pose {
+            pos: Vec2::new(
+                pos.x.rem_euclid(screen::WIDTH),
+                pos.y.rem_euclid(screen::HEIGHT),
+            ),
+            rot: self.rot + (other.rot - self.rot) * s,
+        }
+    }
+}
+
+struct Sample {
+    t: f64,
+    ships: HashMap<String, Pose>,
+    asteroids: HashMap<String, Pose>,
+}
+
+/// Poses of the remote entities received from the host.
+///
+/// Remote entities are displayed `DELAY` seconds in the past, between the two
+/// snapshots around that time, so they move smoothly although snapshots are
+/// only sent every 5 ticks.
+pub struct Interpolation {
+    samples: VecDeque<Sample>,
+}
+
+impl Interpolation {
+    pub const DELAY: f64 = 0.1;
+    /// Samples older than this are not needed anymore.
+    const MAX_AGE: f64 = 1.;
+
+    pub fn new() -> Self {
+        Self {
+            samples: VecDeque::new(),
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.samples.clear();
+    }
+
+    /// Record the state received at `t`, the ship of player `name` is
+    /// predicted and not interpolated.
+    pub fn record(&mut self, t: f64, name: &str, players: &[Ship], asteroids: &mut Asteroids) {
+        let ships = players
+            .iter()
+            .filter(|ship| ship.name() != name)
+            .map(|ship| {
+                (
+                    ship.name(),
+                    Pose {
+                        pos: ship.pos(),
+                        rot: ship.rot(),
+                    },
+                )
+            })
+            .collect();
+        let asteroids = asteroids
+            .get_asteroids()
+            .iter()
+            .map(|(key, asteroid)| {
+                (
+                    key.clone(),
+                    Pose {
+                        pos: asteroid.pos(),
+                        rot: asteroid.rot(),
+                    },
+                )
+            })
+            .collect();
+        self.samples.push_back(Sample {
+            t,
+            ships,
+            asteroids,
+        });
+        while self
+            .samples
+            .front()
+            .is_some_and(|sample| t - sample.t > Interpolation::MAX_AGE)
+        {
+            self.samples.pop_front();
+        }
+    }
+
+    /// Pose to display at `t` of the ship of player `name`.
+    pub fn ship(&self, name: &str, t: f64) -> Option<Pose> {
+        self.pose(t, |sample| sample.ships.get(name))
+    }
+
+    /// Pose to display at `t` of the asteroid `key`.
+    pub fn asteroid(&self, key: &str, t: f64) -> Option<Pose> {
+        self.pose(t, |sample| sample.asteroids.get(key))
+    }
+
+    fn pose<'a>(&'a self, t: f64, get: impl Fn(&'a Sample) -> Option<&'a Pose>) -> Option<Pose> {
+        let render_t = t - Interpolation::DELAY;
+        let samples: Vec<(f64, &Pose)> = self
+            .samples
+            .iter()
+            .filter_map(|sample| get(sample).map(|pose| (sample.t, pose)))
+            .collect();
+        let next = samples.iter().position(|(t, _)| *t > render_t);
+        match next {
+            // Not received long enough ago, display the oldest pose.
+            Some(0) => samples.first().map(|(_, pose)| **pose),
+            Some(next) => {
+                let (t0, pose0) = samples[next - 1];
+                let (t1, pose1) = samples[next];
+                Some(pose0.lerp(pose1, ((render_t - t0) / (t1 - t0)) as f32))
+            }
+            // No snapshot after, do not guess.
+            None => samples.last().map(|(_, pose)| **pose),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use planetoid_core::asteroid::Asteroid;
+
+    fn ship(x: f32, y: f32, rot: f32) -> Ship {
+        let mut ship = Ship::new_pos(String::from("Guest"), Vec2::new(x, y));
+        ship.set_rot(rot);
+        ship
+    }
+
+    #[test]
+    fn interpolation_test() {
+        let mut interpolation = Interpolation::new();
+        let mut asteroids = Asteroids::default();
+        asteroids.add_asteroid(
+            String::from("host"),
+            Asteroid::new_pos_and_size(100., 100., 10.),
+        );
+        let local = Ship::new_pos(String::from("Uggla"), Vec2::new(1., 1.));
+        interpolation.record(
+            1.,
+            "Uggla",
+            &[local.clone(), ship(10., 20., 0.)],
+            &mut asteroids,
+        );
+        interpolation.record(1.1, "Uggla", &[local, ship(20., 40., 10.)], &mut asteroids);
+
+        let pose = interpolation.ship("Guest", 1.15).unwrap();
+        assert!((pose.pos - Vec2::new(15., 30.)).length() < 0.01);
+        assert!((pose.rot - 5.).abs() < 0.01);
+        // Before the first snapshot and after the last one.
+        assert_eq!(
+            interpolation.ship("Guest", 0.5).unwrap().pos,
+            Vec2::new(10., 20.)
+        );
+        assert_eq!(
+            interpolation.ship("Guest", 3.).unwrap().pos,
+            Vec2::new(20., 40.)
+        );
+        // The local ship is predicted.
+        assert_eq!(interpolation.ship("Uggla", 1.15), None);
+        assert_eq!(
+            interpolation.asteroid("host_000000", 1.15).unwrap().pos,
+            Vec2::new(100., 100.)
+        );
+        assert_eq!(interpolation.asteroid("host_000001", 1.15), None);
+
+        interpolation.clear();
+        assert_eq!(interpolation.ship("Guest", 1.15), None);
+    }
+
+    #[test]
+    fn interpolation_wrap_around_test() {
+        let mut interpolation = Interpolation::new();
+        let mut asteroids = Asteroids::default();
+        interpolation.record(
+            1.,
+            "Uggla",
+            &[ship(screen::WIDTH - 4., 10., 0.)],
+            &mut asteroids,
+        );
+        interpolation.record(1.1, "Uggla", &[ship(6., 10., 0.)], &mut asteroids);
+
+        // Through the right edge instead of across the screen.
+        let x = interpolation.ship("Guest", 1.13).unwrap().pos.x;
+        assert!((x - (screen::WIDTH - 1.)).abs() < 0.01);
+        let x = interpolation.ship("Guest", 1.17).unwrap().pos.x;
+        assert!((x - 3.).abs() < 0.01);
+    }
+
+    #[test]
+    fn interpolation_max_age_test() {
+        let mut interpolation = Interpolation::new();
+        let mut asteroids = Asteroids::default();
+        interpolation.record(1., "Uggla", &[ship(10., 20., 0.)], &mut asteroids);
+        interpolation.record(3., "Uggla", &[], &mut asteroids);
+        assert_eq!(interpolation.ship("Guest", 1.1), None);
+    }
+}
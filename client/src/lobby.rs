@@ -0,0 +1,403 @@
+use crate::netsim::NetConditions;
+use crate::network::{
+    lobby_url, Codec, Connection, Message, NetMessage, NetworkError, NetworkEvent,
+};
+use macroquad::prelude::*;
+use serde::Deserialize;
+use std::sync::mpsc::TryRecvError;
+
+/// Room open on the relay and the names of its players.
+#[derive(Deserialize, Debug, PartialEq)]
+pub struct RoomInfo {
+    pub name: String,
+    pub players: Vec<String>,
+}
+
+/// List of the rooms sent by the relay every time it changes.
+#[derive(Deserialize, Debug, PartialEq)]
+struct RoomList {
+    rooms: Vec<RoomInfo>,
+}
+
+/// Room and network mode chosen by the player.
+#[derive(Debug, PartialEq)]
+pub struct Choice {
+    /// `None` for the default room of a server without lobby.
+    pub room: Option<String>,
+    pub mode: String,
+}
+
+/// Characters allowed in the name of a new room.
+fn is_room_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '-' || c == '_' || c == ' '
+}
+
+/// Return why `name` cannot join `room`, players are known by their name.
+fn check_join(room: &RoomInfo, name: &str) -> Result<(), String> {
+    if room.players.iter().any(|player| player == name) {
+        Err(format!("{} is already playing in {}", name, room.name))
+    } else {
+        Ok(())
+    }
+}
+
+/// Lobby of the relay, where players choose a room before the game starts.
+///
+/// The host creates a room and starts the game once the other players are
+/// there, guests and spectators join a room and start with the first
+/// snapshot of the host.
+pub struct Lobby {
+    connection: Connection,
+    rooms: Vec<RoomInfo>,
+    error: Option<NetworkError>,
+}
+
+impl Lobby {
+    pub fn open(host: &str, port: u16, conditions: NetConditions) -> Result<Self, NetworkError> {
+        let url = lobby_url(host, port)?;
+        Ok(Self {
+            connection: Connection::open_simulated(url, conditions),
+            rooms: Vec::new(),
+            error: None,
+        })
+    }
+
+    /// Receive the rooms sent by the relay.
+    fn update(&mut self) {
+        loop {
+            match self.connection.try_recv() {
+                Ok(NetworkEvent::Connected) => self.error = None,
+                Ok(NetworkEvent::Message(Message::Text(text))) => {
+                    match serde_json::from_str::<RoomList>(&text) {
+                        Ok(list) => self.rooms = list.rooms,
+                        Err(e) => log::warn!("Cannot decode the rooms: {}", e),
+                    }
+                }
+                Ok(NetworkEvent::Message(_)) => (),
+                Ok(NetworkEvent::Disconnected(e)) => self.error = Some(e),
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => {
+                    self.error = Some(NetworkError::Disconnected);
+                    break;
+                }
+            }
+        }
+    }
+
+    fn players(&self, room: &str) -> &[String] {
+        self.rooms
+            .iter()
+            .find(|info| info.name == room)
+            .map(|info| info.players.as_slice())
+            .unwrap_or_default()
+    }
+
+    /// List the rooms until the player chooses one, `None` if the player
+    /// quits. The worker has no lobby, the player hosts its default room.
+    pub async fn choose(&mut self, name: &str) -> Option<Choice> {
+        let mut selected = 0;
+        // Name of the room being created.
+        let mut new_room: Option<String> = None;
+        let mut message: Option<String> = None;
+        loop {
+            self.update();
+            if self.error.as_ref().is_some_and(NetworkError::is_not_found) {
+                log::warn!("No lobby on the server, hosting the default room");
+                return Some(Choice {
+                    room: None,
+                    mode: String::from("host"),
+                });
+            }
+            selected = selected.min(self.rooms.len().saturating_sub(1));
+
+            match new_room.as_mut() {
+                Some(room) => {
+                    while let Some(c) = get_char_pressed() {
+                        if is_room_char(c) {
+                            room.push(c);
+                        }
+                    }
+                    if is_key_pressed(KeyCode::Backspace) {
+                        room.pop();
+                    }
+                    if is_key_pressed(KeyCode::Escape) {
+                        new_room = None;
+                    } else if is_key_pressed(KeyCode::Enter) {
+                        let room = room.trim().to_string();
+                        if room.is_empty() {
+                            message = Some(String::from("The room needs a name"));
+                        } else if self.rooms.iter().any(|info| info.name == room) {
+                            message = Some(format!("{} already exists, join it instead", room));
+                        } else {
+                            return Some(Choice {
+                                room: Some(room),
+                                mode: String::from("host"),
+                            });
+                        }
+                    }
+                }
+                None => {
+                    // Characters typed before creating a room are not part of its name.
+                    while get_char_pressed().is_some() {}
+                    if is_key_pressed(KeyCode::Escape) {
+                        return None;
+                    }
+                    if is_key_pressed(KeyCode::Up) {
+                        selected = selected.saturating_sub(1);
+                    }
+                    if is_key_pressed(KeyCode::Down) {
+                        selected += 1;
+                    }
+                    if is_key_pressed(KeyCode::C) {
+                        new_room = Some(String::new());
+                        message = None;
+                    }
+                    let mode = if is_key_pressed(KeyCode::G) {
+                        Some("guest")
+                    } else if is_key_pressed(KeyCode::S) {
+                        Some("spectator")
+                    } else {
+                        None
+                    };
+                    if let (Some(mode), Some(room)) = (mode, self.rooms.get(selected)) {
+                        match check_join(room, name) {
+                            Ok(()) => {
+                                return Some(Choice {
+                                    room: Some(room.name.clone()),
+                                    mode: mode.to_string(),
+                                })
+                            }
+                            Err(e) => message = Some(e),
+                        }
+                    }
+                }
+            }
+
+            self.draw_rooms(selected, new_room.as_deref(), message.as_deref());
+            next_frame().await;
+        }
+    }
+
+    pub fn close(self) {
+        self.connection.close();
+    }
+
+    /// Wait in the chosen room until the host starts the game. The host
+    /// starts it with enter, guests and spectators once they receive the
+    /// first snapshot. Return false if the player quits.
+    pub async fn wait_start(mut self, connection: &mut Connection, mode: &str, room: &str) -> bool {
+        // Keys pressed in the rooms list do not start the game.
+        next_frame().await;
+        let started = loop {
+            self.update();
+            if is_key_pressed(KeyCode::Escape) {
+                break false;
+            }
+            if mode == "host" {
+                // Messages of the players joining are handled by the game.
+                if is_key_pressed(KeyCode::Enter) {
+                    break true;
+                }
+            } else if self.host_started(connection) {
+                break true;
+            }
+
+            self.draw_room(mode, room);
+            next_frame().await;
+        };
+        self.connection.close();
+        started
+    }
+
+    /// Whether the host sent a snapshot, the messages before are dropped and
+    /// the game asks for a keyframe anyway.
+    fn host_started(&mut self, connection: &mut Connection) -> bool {
+        loop {
+            match connection.try_recv() {
+                Ok(NetworkEvent::Message(msg)) => {
+                    if let Ok(NetMessage::GameData(_)) = Codec::decode(&msg) {
+                        return true;
+                    }
+                }
+                Ok(NetworkEvent::Connected) => self.error = None,
+                Ok(NetworkEvent::Disconnected(e)) => self.error = Some(e),
+                Err(TryRecvError::Empty) => return false,
+                Err(TryRecvError::Disconnected) => {
+                    self.error = Some(NetworkError::Disconnected);
+                    return false;
+                }
+            }
+        }
+    }
+
+    fn draw_rooms(&self, selected: usize, new_room: Option<&str>, message: Option<&str>) {
+        clear_background(LIGHTGRAY);
+        let mut y = 60.;
+        draw_text("Planetoid lobby", 40., y, 50., DARKGRAY);
+        y += 60.;
+        if self.rooms.is_empty() {
+            draw_text("No room yet, create one.", 40., y, 30., DARKGRAY);
+            y += 40.;
+        }
+        for (i, room) in self.rooms.iter().enumerate() {
+            let text = format!("{}  ({})", room.name, room.players.join(", "));
+            let (marker, color) = if i == selected {
+                ("> ", BLACK)
+            } else {
+                ("  ", DARKGRAY)
+            };
+            draw_text(&format!("{}{}", marker, text), 40., y, 30., color);
+            y += 35.;
+        }
+
+        y += 20.;
+        match new_room {
+            Some(room) => draw_text(
+                &format!("New room: {}_  [enter] create, [esc] cancel", room),
+                40.,
+                y,
+                30.,
+                BLACK,
+            ),
+            None => draw_text(
+                "[up]/[down] select, [G] join as guest, [S] join as spectator, [C] create a room, [esc] quit",
+                40.,
+                y,
+                20.,
+                DARKGRAY,
+            ),
+        }
+        if let Some(message) = message {
+            draw_text(message, 40., y + 40., 30., RED);
+        }
+        self.draw_error();
+    }
+
+    fn draw_room(&self, mode: &str, room: &str) {
+        clear_background(LIGHTGRAY);
+        let mut y = 60.;
+        draw_text(&format!("Room {}", room), 40., y, 50., DARKGRAY);
+        y += 60.;
+        for player in self.players(room) {
+            draw_text(player, 40., y, 30., BLACK);
+            y += 35.;
+        }
+
+        y += 20.;
+        let text = if mode == "host" {
+            "Press [enter] to start the game."
+        } else {
+            "Waiting for the host to start the game..."
+        };
+        draw_text(text, 40., y, 30., DARKGRAY);
+        self.draw_error();
+    }
+
+    fn draw_error(&self) {
+        if let Some(error) = &self.error {
+            draw_text(
+                &format!("{}, reconnecting...", error),
+                5.,
+                screen_height() - 10.,
+                30.,
+                RED,
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::network::game_url;
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::thread::{self, sleep};
+    use std::time::{Duration, Instant};
+
+    fn room(name: &str, players: &[&str]) -> RoomInfo {
+        RoomInfo {
+            name: String::from(name),
+            players: players.iter().map(|player| player.to_string()).collect(),
+        }
+    }
+
+    #[test]
+    fn room_list_decode_test() {
+        let list: RoomList =
+            serde_json::from_str(r#"{"rooms":[{"name":"game1","players":["Guest","Uggla"]}]}"#)
+                .unwrap();
+        assert_eq!(list.rooms, vec![room("game1", &["Guest", "Uggla"])]);
+    }
+
+    #[test]
+    fn check_join_test() {
+        let game1 = room("game1", &["Uggla"]);
+        assert!(check_join(&game1, "Guest").is_ok());
+        assert_eq!(
+            check_join(&game1, "Uggla"),
+            Err(String::from("Uggla is already playing in game1"))
+        );
+    }
+
+    #[test]
+    fn is_room_char_test() {
+        assert!("Game 1_b-é".chars().all(is_room_char));
+        assert!(!is_room_char('/'));
+        assert!(!is_room_char('\u{8}'));
+    }
+
+    #[test]
+    fn lobby_relay_test() {
+        let relay = planetoid_relay::Relay::bind("127.0.0.1:0").unwrap();
+        let port = relay.local_addr().unwrap().port();
+        let relay = relay.spawn();
+
+        let mut lobby = Lobby::open("127.0.0.1", port, NetConditions::default()).unwrap();
+        let host = Connection::open_simulated(
+            game_url("127.0.0.1", port, Some("game1"), "Uggla").unwrap(),
+            NetConditions::default(),
+        );
+
+        let start = Instant::now();
+        while lobby.players("game1").is_empty() {
+            assert!(start.elapsed() < Duration::from_secs(5));
+            sleep(Duration::from_millis(10));
+            lobby.update();
+        }
+        assert_eq!(lobby.players("game1"), &[String::from("Uggla")]);
+        assert!(lobby.players("game2").is_empty());
+
+        host.close();
+        lobby.connection.close();
+        relay.stop().unwrap();
+    }
+
+    #[test]
+    fn lobby_not_found_test() {
+        // A server without lobby, like the worker.
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = [0; 1024];
+            let _ = stream.read(&mut request).unwrap();
+            stream
+                .write_all(b"HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\n\r\n")
+                .unwrap();
+        });
+
+        let mut lobby = Lobby::open("127.0.0.1", port, NetConditions::default()).unwrap();
+        let start = Instant::now();
+        while lobby.error.is_none() {
+            assert!(start.elapsed() < Duration::from_secs(5));
+            sleep(Duration::from_millis(10));
+            lobby.update();
+        }
+        assert!(lobby.error.as_ref().unwrap().is_not_found());
+        assert!(!NetworkError::Disconnected.is_not_found());
+
+        server.join().unwrap();
+        lobby.close();
+    }
+}
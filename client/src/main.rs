@@ -1,37 +1,58 @@
-mod asteroid;
-mod bullet;
-mod collision;
+mod chat;
+mod clock;
 mod gameover;
+mod handshake;
+mod interpolation;
 #[cfg(not(target_arch = "wasm32"))]
+mod lobby;
+#[cfg(not(target_arch = "wasm32"))]
+mod netsim;
 mod network;
+mod render;
+mod roster;
 mod screen;
-mod ship;
+#[cfg(not(target_arch = "wasm32"))]
+mod server;
+mod session;
+mod snapshot;
 mod sound;
-use crate::asteroid::Asteroids;
-use crate::collision::manage_collisions;
+mod spectator;
+mod stats;
+#[cfg(target_arch = "wasm32")]
+mod wasm_network;
+use crate::clock::Ticker;
+use crate::gameover::manage_gameover;
+use crate::handshake::Protocol;
+use crate::interpolation::Interpolation;
+#[cfg(not(target_arch = "wasm32"))]
+use crate::lobby::Lobby;
 #[cfg(not(target_arch = "wasm32"))]
+use crate::netsim::{parse_probability, NetConditions};
 use crate::network::{
-    connect_stream, connect_ws, deserialize_host_data, serialize_guest_data, serialize_host_data,
-    wait_synchronization_data,
+    deserialize_host_data, game_url, migrate_host, serialize_guest_data, serialize_guest_inputs,
+    serialize_host_data, wait_synchronization_data, Codec, Connection, NetMessage, NetworkError,
+    NetworkEvent,
 };
-use crate::{gameover::manage_gameover, ship::Ship};
+use crate::render::{draw_asteroid, draw_bullet, draw_ship};
+use crate::session::Session;
+use crate::stats::NetStats;
 use macroquad::prelude::*;
+use planetoid_core::asteroid::Asteroids;
+use planetoid_core::collision::Collided;
+use planetoid_core::input::Input;
+use planetoid_core::ship::Ship;
+use planetoid_core::world::{self, World};
 #[cfg(not(target_arch = "wasm32"))]
 use simple_logger::SimpleLogger;
 use sound::Sound;
-#[cfg(not(target_arch = "wasm32"))]
-use std::{net::TcpStream, sync::mpsc, thread, thread::sleep, time::Duration};
+use spectator::SpectatorCamera;
+use std::sync::mpsc;
 use structopt::clap::{crate_name, crate_version};
 use structopt::StructOpt;
-#[cfg(not(target_arch = "wasm32"))]
-use tungstenite::Message;
-#[cfg(not(target_arch = "wasm32"))]
-use url::Url;
 
 #[derive(StructOpt, Debug)]
 #[structopt(name = crate_name!(), version = crate_version!())]
 /// Planetoid is an asteroid clone.
-
 struct Opt {
     /// Debug mode (ϕ (error), -d (info), -dd (debug), -ddd (trace))
     #[cfg(not(target_arch = "wasm32"))]
@@ -39,25 +60,26 @@ struct Opt {
     debug: u8,
 
     /// Host
-    #[cfg(not(target_arch = "wasm32"))]
     #[structopt(short, long, default_value = "localhost")]
     host: String,
 
     /// Port
-    #[cfg(not(target_arch = "wasm32"))]
     #[structopt(short, long, default_value = "8080")]
     port: u16,
 
+    /// Room of the game, requires the relay (default: shared room of the worker)
+    #[structopt(short, long)]
+    room: Option<String>,
+
     /// God mode
     #[structopt(short, long)]
     god: bool,
 
-    /// Network mode
-    #[structopt(short, long, default_value = "host", possible_values = &["host","guest","spectator"])]
-    mode: String,
+    /// Network mode, a server hosts the game without window (default: choose a room in the lobby of the relay)
+    #[structopt(short, long, possible_values = MODES)]
+    mode: Option<String>,
 
     /// Solo mode, do not connect to network
-    #[cfg(not(target_arch = "wasm32"))]
     #[structopt(short, long, conflicts_with = "mode")]
     solo: bool,
 
@@ -68,16 +90,71 @@ struct Opt {
     /// Player name
     #[structopt(short, long, default_value = "planetoid")]
     name: String,
+
+    /// Seconds without message from a player before the host drops its ship
+    #[structopt(short, long, default_value = "5")]
+    timeout: f64,
+
+    /// Guest sends its whole ship instead of its inputs, for hosts that do not
+    /// simulate the ships of the guests
+    #[structopt(long)]
+    ship_state: bool,
+
+    /// Network messages encoding
+    #[structopt(short, long, default_value = "json", possible_values = Codec::VALUES)]
+    codec: Codec,
+
+    /// Seed of the simulation of the host, the same seed replays the same
+    /// asteroid fields (default: random)
+    #[structopt(long)]
+    seed: Option<u64>,
+
+    /// Simulated latency in ms of the messages sent and received, for testing
+    #[cfg(not(target_arch = "wasm32"))]
+    #[structopt(long, default_value = "0")]
+    sim_latency: u64,
+
+    /// Simulated jitter in ms, random variation of the latency
+    #[cfg(not(target_arch = "wasm32"))]
+    #[structopt(long, default_value = "0")]
+    sim_jitter: u64,
+
+    /// Simulated probability for a message to be overtaken by the next ones
+    #[cfg(not(target_arch = "wasm32"))]
+    #[structopt(long, default_value = "0", parse(try_from_str = parse_probability))]
+    sim_reorder: f64,
+
+    /// Simulated probability for a message to be lost
+    #[cfg(not(target_arch = "wasm32"))]
+    #[structopt(long, default_value = "0", parse(try_from_str = parse_probability))]
+    sim_drop: f64,
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl Opt {
+    fn net_conditions(&self) -> NetConditions {
+        NetConditions {
+            latency: std::time::Duration::from_millis(self.sim_latency),
+            jitter: std::time::Duration::from_millis(self.sim_jitter),
+            reorder: self.sim_reorder,
+            drop: self.sim_drop,
+        }
+    }
 }
 
+#[cfg(not(target_arch = "wasm32"))]
+const MODES: &[&str] = &["host", "guest", "spectator", "server"];
+#[cfg(target_arch = "wasm32")]
+const MODES: &[&str] = &["host", "guest", "spectator"];
+
 const MAX_ASTEROIDS: usize = 10;
 
 fn window_conf() -> Conf {
     Conf {
         window_title: String::from("Planetoid"),
         fullscreen: false,
-        window_width: 1024,
-        window_height: 768,
+        window_width: screen::WIDTH as i32,
+        window_height: screen::HEIGHT as i32,
         window_resizable: false,
 
         ..Default::default()
@@ -95,6 +172,19 @@ fn get_log_level(debug_occurence: u8) -> log::LevelFilter {
     }
 }
 
+fn display_score(score: u32) {
+    let text = format!("Score: {}", score);
+    let font_size = 30.;
+    let text_size = measure_text(&text, None, font_size as _, 1.0);
+    draw_text(
+        &text,
+        screen_width() - text_size.width - 5.,
+        20.,
+        font_size,
+        DARKGRAY,
+    )
+}
+
 fn display_fps(fps: &mut i32, frame_t: f64, fps_refresh: &mut f64) {
     if frame_t - *fps_refresh > 0.2 {
         *fps = get_fps();
@@ -105,12 +195,55 @@ fn display_fps(fps: &mut i32, frame_t: f64, fps_refresh: &mut f64) {
     draw_text(&text, 5., 20., font_size, DARKGRAY)
 }
 
-#[macroquad::main(window_conf)]
-async fn main() {
-    // Seed random generator
+fn display_net_stats(stats: &NetStats) {
+    let font_size = 20.;
+    for (i, line) in stats.lines().iter().enumerate() {
+        draw_text(line, 5., 40. + i as f32 * font_size, font_size, DARKGRAY);
+    }
+}
+
+fn display_network_error(error: &NetworkError) {
+    let text = format!("{}, reconnecting...", error);
+    let font_size = 30.;
+    draw_text(&text, 5., screen_height() - 10., font_size, RED)
+}
+
+/// Display why the game cannot go on until the player quits.
+async fn display_fatal_error(error: &NetworkError) {
+    loop {
+        clear_background(LIGHTGRAY);
+        let text = error.to_string();
+        let font_size = 30.;
+        let text_size = measure_text(&text, None, font_size as _, 1.0);
+        draw_text(
+            &text,
+            screen_width() / 2. - text_size.width / 2.,
+            screen_height() / 2. - text_size.height / 2.,
+            font_size,
+            RED,
+        );
+        if is_key_down(KeyCode::Escape) || is_key_down(KeyCode::Enter) {
+            return;
+        }
+        next_frame().await;
+    }
+}
+
+fn main() {
+    // Seed random generator, for the randomness outside of the simulation
     rand::srand(miniquad::date::now() as u64);
 
+    #[cfg(not(target_arch = "wasm32"))]
     let opt = Opt::from_args();
+    #[cfg(target_arch = "wasm32")]
+    let opt = {
+        let mut args = wasm_network::args();
+        // The browser game is played solo unless a network mode is given.
+        if !args.iter().any(|arg| arg == "--mode") {
+            args.push(String::from("--solo"));
+        }
+        Opt::from_iter(args)
+    };
 
     #[cfg(not(target_arch = "wasm32"))]
     let log_level = get_log_level(opt.debug);
@@ -122,295 +255,594 @@ async fn main() {
         .init()
         .unwrap();
     log::debug!("{:#?}", opt);
+
+    let seed = opt.seed.unwrap_or(miniquad::date::now() as u64);
+    log::info!("Simulation seed: {}", seed);
+
+    #[cfg(not(target_arch = "wasm32"))]
+    if opt.mode.as_deref() == Some("server") {
+        match game_url(&opt.host, opt.port, opt.room.as_deref(), &opt.name) {
+            Ok(url) => server::run(
+                url,
+                opt.net_conditions(),
+                opt.codec,
+                &opt.name,
+                opt.timeout,
+                seed,
+            ),
+            Err(e) => log::error!("{}", e),
+        }
+        return;
+    }
+
+    macroquad::Window::from_config(window_conf(), game(opt, seed));
+}
+
+async fn game(mut opt: Opt, seed: u64) {
     log::info!("Starting game.");
 
+    // Without network mode, the player chooses a room in the lobby and the
+    // game starts once the host is ready.
+    #[cfg(not(target_arch = "wasm32"))]
+    let mut lobby = None;
+    #[cfg(not(target_arch = "wasm32"))]
+    if opt.mode.is_none() && !opt.solo {
+        let lobby = match Lobby::open(&opt.host, opt.port, opt.net_conditions()) {
+            Ok(new_lobby) => lobby.insert(new_lobby),
+            Err(e) => {
+                log::error!("{}", e);
+                return;
+            }
+        };
+        match lobby.choose(&opt.name).await {
+            Some(choice) => {
+                opt.room = choice.room;
+                opt.mode = Some(choice.mode);
+            }
+            None => return,
+        }
+    }
+
     let mut show_fps = opt.fps;
     let mut fps: i32 = 0;
     let mut gameover = false;
     let mut gameover_msg_sent = false;
-    #[cfg(not(target_arch = "wasm32"))]
-    let mut host_msg_received: bool = false;
     // Timing values
-    let mut lastshot_t = get_time();
-    let mut thrust_t = get_time();
-    let mut fps_t = get_time();
-    let mut debounce_t = get_time();
+    // Shots are limited by the host with the time of the host.
+    let mut lastshot_t = f64::MIN;
+    let mut thrust_t = clock::now();
+    let mut fps_t = clock::now();
+    let mut debounce_t = clock::now();
+    let mut heartbeat_t = clock::now();
 
     let mut sound = Sound::new().await;
 
-    #[allow(unused_mut)]
-    let mut sync_t: f64 = 0.0;
-    let mut players: Vec<Ship> = vec![Ship::new(String::from(&opt.name))];
+    // Guests take the seed of the host.
+    let mut world = World::new(screen::WIDTH, screen::HEIGHT, seed);
+    let mut players: Vec<Ship> = vec![Ship::new(String::from(&opt.name), &mut world)];
 
-    let mut asteroids: Asteroids = Asteroids::generate_field(opt.name.clone(), 0);
-    if opt.mode == "host" {
-        asteroids = Asteroids::generate_field(opt.name.clone(), MAX_ASTEROIDS);
+    let mode = opt.mode.as_deref().unwrap_or("host");
+    let mut session = Session::new(&opt.name, mode, opt.codec, opt.timeout, seed);
+    if opt.ship_state {
+        session.handshake.send_ship_state();
+    }
+    let mut asteroids: Asteroids = Asteroids::default();
+    if session.mode == "host" {
+        asteroids = Asteroids::generate_field(opt.name.clone(), MAX_ASTEROIDS, &mut world);
+        session.snapshots.new_round(session.clock_sync.now());
     }
 
-    #[cfg(not(target_arch = "wasm32"))]
-    let (tx_from_socket, rx_from_socket) = mpsc::channel();
-    #[cfg(not(target_arch = "wasm32"))]
-    let (tx_to_socket, rx_to_socket) = mpsc::channel();
-
-    #[cfg(not(target_arch = "wasm32"))]
-    if !opt.solo {
-        let url = Url::parse(&format!(
-            "ws://{}:{}/gamedata/{}",
-            &opt.host, &opt.port, &opt.name
-        ))
-        .expect("Cannot parse url.");
-
-        // Thread to manage network web socket
-        // This thread uses a channel to pass messages to the main thread (game)
-        thread::spawn(move || {
-            let stream: TcpStream = connect_stream(&url);
-            let (mut socket, _response) = connect_ws(url, &stream).unwrap();
-            loop {
-                match rx_to_socket.try_recv() {
-                    Ok(msg) => socket
-                        .write_message(Message::Text(msg))
-                        .expect("Cannot write to WebSocket."),
-                    Err(mpsc::TryRecvError::Empty) => (),
-                    Err(mpsc::TryRecvError::Disconnected) => panic!("Client disconnected."),
-                };
+    let mut interpolation = Interpolation::new();
+    let mut spectator_camera = SpectatorCamera::new();
+    // Messages are only exchanged while online, the game goes on offline
+    // while the connection tries to reconnect.
+    let mut online = !opt.solo;
+    let mut network_error: Option<NetworkError> = None;
+
+    let mut connection = None;
+    if online {
+        let url = match game_url(&opt.host, opt.port, opt.room.as_deref(), &opt.name) {
+            Ok(url) => url,
+            Err(e) => {
+                log::error!("{}", e);
+                return;
+            }
+        };
+        #[cfg(not(target_arch = "wasm32"))]
+        let mut new_connection = Connection::open_simulated(url, opt.net_conditions());
+        #[cfg(target_arch = "wasm32")]
+        let new_connection = Connection::open(url);
 
-                if let Ok(msg) = socket.read_message() {
-                    tx_from_socket.send(msg).unwrap();
+        #[cfg(not(target_arch = "wasm32"))]
+        if let Some(lobby) = lobby.take() {
+            match opt.room.as_deref() {
+                Some(room) => {
+                    if !lobby
+                        .wait_start(&mut new_connection, &session.mode, room)
+                        .await
+                    {
+                        new_connection.close();
+                        return;
+                    }
                 }
-                sleep(Duration::from_millis(5));
+                // Without lobby, the game starts right away.
+                None => lobby.close(),
             }
-        });
+        }
+        let connection = connection.insert(new_connection);
 
-        wait_synchronization_data(
-            &rx_from_socket,
-            &tx_to_socket,
-            &opt.name,
-            &opt.mode,
+        if let Err(e) = wait_synchronization_data(
+            connection,
+            &mut session,
             &mut asteroids,
             &mut players,
             &mut gameover,
-            &mut host_msg_received,
-            &mut sync_t,
-        );
+            &mut world,
+        )
+        .await
+        {
+            log::error!("{}", e);
+            display_fatal_error(&e).await;
+            return;
+        }
     }
 
-    let mut frame_count: u32 = 0;
-    let time_before_entering_loop = get_time();
+    // Ticks since the last snapshot sent by the host.
+    let mut tick_count: u32 = 0;
+    let mut ticker = Ticker::new(clock::now());
 
     // Game loop
     loop {
-        #[cfg(not(target_arch = "wasm32"))]
-        if !opt.solo {
+        if let Some(connection) = connection.as_mut() {
+            let applied = session.snapshots.applied();
+            let mut reconnected = false;
             loop {
-                let _received = match rx_from_socket.try_recv() {
-                    Ok(msg) => {
-                        deserialize_host_data(
-                            &opt.name,
-                            &opt.mode,
+                match connection.try_recv() {
+                    Ok(NetworkEvent::Connected) => {
+                        log::info!("Connected.");
+                        reconnected = true;
+                    }
+                    Ok(NetworkEvent::Message(msg)) => {
+                        if let Err(e) = deserialize_host_data(
+                            &mut session,
                             msg,
                             &mut asteroids,
                             &mut players,
                             &mut gameover,
-                            &mut host_msg_received,
-                            &mut sync_t,
-                        );
+                            &mut world,
+                        ) {
+                            log::warn!("Dropping message: {}", e);
+                        }
+                    }
+                    Ok(NetworkEvent::Disconnected(e)) => {
+                        network_error = Some(e);
+                        online = false;
+                        reconnected = false;
+                    }
+                    Err(mpsc::TryRecvError::Empty) => break,
+                    Err(mpsc::TryRecvError::Disconnected) => {
+                        network_error = Some(NetworkError::Disconnected);
+                        online = false;
+                        break;
                     }
-                    Err(mpsc::TryRecvError::Empty) => (break),
-                    Err(mpsc::TryRecvError::Disconnected) => panic!("Disconnected"),
                 };
             }
 
-            if frame_count > 4 && opt.mode == "host" {
-                tx_to_socket
-                    .send(serialize_host_data(
+            session.stats.update(clock::now(), connection.sent());
+
+            if session.snapshots.applied() != applied {
+                interpolation.record(clock::now(), &opt.name, &players, &mut asteroids);
+            }
+
+            // Predict the local ship from the last state confirmed by the host.
+            if let Some((acked, confirmed)) = session.snapshots.take_confirmed() {
+                if let Some(ship) = players.iter_mut().find(|ship| ship.name() == opt.name) {
+                    *ship = confirmed;
+                    session.input_log.reconcile(acked, ship, &world);
+                }
+            }
+
+            // Run the handshake again, the ship of this player is kept.
+            if reconnected {
+                online = true;
+                network_error = None;
+                session.snapshots.reset();
+                session.roster.clear();
+                interpolation.clear();
+                if let Err(e) = wait_synchronization_data(
+                    connection,
+                    &mut session,
+                    &mut asteroids,
+                    &mut players,
+                    &mut gameover,
+                    &mut world,
+                )
+                .await
+                {
+                    network_error = Some(e);
+                    online = false;
+                }
+                ticker.reset(clock::now());
+            }
+        }
+
+        // A guest falls back to sending its whole ship to a host that does
+        // not simulate it.
+        let ship_state = opt.ship_state || !session.handshake.host_supports(Protocol::INPUTS);
+
+        // The keys of this frame went to the chat if it was open.
+        let typing = session.chat.is_typing();
+        if let Some(connection) = connection.as_ref() {
+            if let Some(msg) = session.chat.update(&opt.name) {
+                connection.send(opt.codec.encode(&msg));
+            }
+        }
+
+        if let Some(connection) = connection.as_ref().filter(|_| online) {
+            if session.mode != "host" {
+                if let Some(lost_host) = session.roster.host_lost(clock::now()) {
+                    migrate_host(
+                        &lost_host,
+                        &mut session,
                         &mut asteroids,
                         &mut players,
-                        &mut gameover,
-                    ))
-                    .unwrap();
-                frame_count = 0;
+                        &mut world,
+                    );
+                }
+            }
+
+            // Drop the players that left without a Leave message, the next
+            // snapshot lets the other players know.
+            if session.mode == "host" && !gameover {
+                for name in session.roster.timed_out(&players, &opt.name, clock::now()) {
+                    log::info!("{} timed out", name);
+                    players.retain(|ship| ship.name() != name);
+                    session.snapshots.remove_peer(&name);
+                    session.inputs.remove(&name);
+                    session.stats.remove(&name);
+                }
+            }
+
+            if tick_count > 4 && session.mode == "host" {
+                connection.send(serialize_host_data(
+                    session.codec,
+                    &session.name,
+                    &mut session.snapshots,
+                    &mut asteroids,
+                    &mut players,
+                    &session.inputs,
+                    &world,
+                ));
+                tick_count = 0;
+            }
+
+            // Say hello to the new host after a migration.
+            if session.mode != "host" {
+                if let Some(hello) =
+                    session
+                        .handshake
+                        .hello(&opt.name, session.mode == "spectator", clock::now())
+                {
+                    connection.send(opt.codec.encode(&hello));
+                }
             }
 
-            if host_msg_received && opt.mode == "guest" {
+            if session.mode == "host" {
+                for msg in session
+                    .handshake
+                    .answers()
+                    .iter()
+                    .chain(&session.clock_sync.pongs())
+                {
+                    connection.send(opt.codec.encode(msg));
+                }
+            } else if let Some(ping) = session.clock_sync.ping(&opt.name) {
+                connection.send(opt.codec.encode(&ping));
+            }
+
+            if session.host_msg_received && session.mode != "host" {
+                connection.send(opt.codec.encode(&NetMessage::Ack {
+                    name: opt.name.clone(),
+                    id: session.snapshots.applied(),
+                }));
+                if session.mode == "guest" && !ship_state {
+                    connection.send(serialize_guest_inputs(
+                        opt.codec,
+                        &opt.name,
+                        session.input_log.take_unsent(),
+                    ));
+                }
                 for ship in players.iter() {
-                    if ship.name() == opt.name {
-                        tx_to_socket
-                            .send(serialize_guest_data(ship, &mut asteroids))
-                            .unwrap();
+                    if ship.name() == opt.name && session.mode == "guest" && ship_state {
+                        connection.send(serialize_guest_data(opt.codec, ship, &mut asteroids));
                     }
                 }
-                host_msg_received = false;
+                session.host_msg_received = false;
             }
         }
 
         if gameover {
             // Send a last message to all guests that the game is over
-            #[cfg(not(target_arch = "wasm32"))]
-            if !opt.solo && opt.mode == "host" && !gameover_msg_sent {
-                tx_to_socket
-                    .send(serialize_host_data(
-                        &mut asteroids,
-                        &mut players,
-                        &mut gameover,
-                    ))
-                    .unwrap();
-                frame_count = 0;
+            if let Some(connection) = connection
+                .as_ref()
+                .filter(|_| online && session.mode == "host" && !gameover_msg_sent)
+            {
+                connection.send(serialize_host_data(
+                    session.codec,
+                    &session.name,
+                    &mut session.snapshots,
+                    &mut asteroids,
+                    &mut players,
+                    &session.inputs,
+                    &world,
+                ));
+                connection.send(opt.codec.encode(&NetMessage::GameOver));
+                tick_count = 0;
                 gameover_msg_sent = true;
             }
 
+            // Let the peers know the host is still there until a new game starts.
+            if let Some(connection) = connection
+                .as_ref()
+                .filter(|_| online && session.mode == "host" && clock::now() - heartbeat_t > 1.)
+            {
+                connection.send(opt.codec.encode(&NetMessage::Heartbeat));
+                heartbeat_t = clock::now();
+            }
+
             manage_gameover(
                 &mut players,
                 &mut asteroids,
-                &opt.mode,
+                &session.mode,
                 &opt.name,
-                &mut frame_count,
+                &mut tick_count,
                 &mut gameover,
                 &mut gameover_msg_sent,
                 &mut sound,
+                &mut world,
+                typing,
             );
+            session.chat.draw(clock::now());
+
+            // Guests and spectators cannot restart without the host, stay on
+            // the gameover screen until reconnected.
+            if let Some(network_error) = &network_error {
+                display_network_error(network_error);
+                if session.mode != "host" {
+                    gameover = true;
+                }
+            }
 
-            // Display frame but do not increase frame_count to not send new messages
+            // Display frame but do not run ticks to not send new messages
             next_frame().await;
+            // The time spent on the gameover screen is not simulated.
+            ticker.reset(clock::now());
 
-            // Guest will be blocked waiting for the next message from the host
+            // New game, peers need a keyframe
+            if !gameover {
+                if session.mode == "host" {
+                    session.snapshots.new_round(session.clock_sync.now());
+                }
+                session.snapshots.reset();
+                session.roster.clear();
+                session.input_log.clear();
+                session.inputs.clear();
+                interpolation.clear();
+            }
+
+            // Guest will be waiting for the next message from the host
             // The host will send a new message as soon as the user will hit enter
-            #[cfg(not(target_arch = "wasm32"))]
-            if !opt.solo {
-                wait_synchronization_data(
-                    &rx_from_socket,
-                    &tx_to_socket,
-                    &opt.name,
-                    &opt.mode,
+            if let Some(connection) = connection.as_mut().filter(|_| online) {
+                if let Err(e) = wait_synchronization_data(
+                    connection,
+                    &mut session,
                     &mut asteroids,
                     &mut players,
                     &mut gameover,
-                    &mut host_msg_received,
-                    &mut sync_t,
-                );
+                    &mut world,
+                )
+                .await
+                {
+                    network_error = Some(e);
+                    online = false;
+                    gameover = session.mode != "host";
+                }
             }
             continue;
         }
 
-        let frame_t = get_time() - time_before_entering_loop;
-        for ship in players.iter_mut() {
-            ship.slow_down();
+        let frame_t = clock::now();
+        let net_t = session.clock_sync.now();
+        world.t = net_t;
+
+        // Keys are read once per frame and apply to every tick of the frame.
+        let key_down = |key| !typing && is_key_down(key);
+        let thrust = key_down(KeyCode::Up);
+        let rotate = if key_down(KeyCode::Right) {
+            1
+        } else if key_down(KeyCode::Left) {
+            -1
+        } else {
+            0
+        };
+        if session.mode == "spectator" {
+            spectator_camera.update(&players, typing);
+        }
+        if key_down(KeyCode::F) && frame_t - debounce_t > 0.2 {
+            show_fps = !show_fps;
+            debounce_t = frame_t;
         }
 
-        if opt.mode != "spectator" {
-            if is_key_down(KeyCode::Up) {
-                for ship in players.iter_mut() {
-                    if ship.name() == opt.name && !ship.collided() {
-                        ship.accelerate();
-                        if frame_t - thrust_t > 0.5 {
-                            sound.thrust();
-                            thrust_t = frame_t;
-                        }
-                    }
-                }
-            }
+        // Escape closing the chat does not quit the game.
+        if cfg!(not(target_arch = "wasm32")) && !typing && is_key_pressed(KeyCode::Escape) {
+            break;
+        }
 
-            if is_key_down(KeyCode::Space) && frame_t - lastshot_t > 0.1 {
-                for ship in players.iter_mut() {
-                    if ship.name() == opt.name && !ship.collided() {
-                        ship.shoot(frame_t);
-                        sound.laser();
-                    }
+        let peer = world::Peer {
+            name: &opt.name,
+            mode: &session.mode,
+            god: opt.god,
+            // The host simulates the ship of the guests sending their inputs,
+            // a guest leaves the asteroids it hits to the host.
+            simulates_own_ship: session.mode != "guest" || ship_state,
+        };
+        // The simulation runs by fixed ticks, whatever the frame rate.
+        for _ in 0..ticker.ticks(frame_t) {
+            let input = if session.mode == "spectator" {
+                None
+            } else {
+                let fire = key_down(KeyCode::Space) && net_t - lastshot_t > 0.1;
+                if fire {
+                    lastshot_t = net_t;
                 }
-                lastshot_t = frame_t;
-            }
-
-            if is_key_down(KeyCode::Right) {
-                for ship in players.iter_mut() {
-                    if ship.name() == opt.name && !ship.collided() {
-                        ship.set_rot(ship.rot() + 5.);
+                Some(Input::new(thrust, rotate, fire, net_t))
+            };
+            if let Some(input) = &input {
+                if players
+                    .iter()
+                    .any(|ship| ship.name() == opt.name && !ship.collided())
+                {
+                    if input.thrust && frame_t - thrust_t > 0.5 {
+                        sound.thrust();
+                        thrust_t = frame_t;
                     }
-                }
-            } else if is_key_down(KeyCode::Left) {
-                for ship in players.iter_mut() {
-                    if ship.name() == opt.name && !ship.collided() {
-                        ship.set_rot(ship.rot() - 5.);
+                    if input.fire {
+                        sound.laser();
                     }
                 }
             }
-        }
-        if is_key_down(KeyCode::F) && frame_t - debounce_t > 0.2 {
-            if show_fps {
-                show_fps = false;
-            } else {
-                show_fps = true;
-            }
-            debounce_t = frame_t;
-        }
-
-        if cfg!(not(target_arch = "wasm32")) && is_key_down(KeyCode::Escape) {
-            break;
-        }
 
-        for ship in players.iter_mut() {
-            ship.update_pos();
-        }
+            let round_over = world::step(
+                &mut world,
+                &mut players,
+                &mut asteroids,
+                &mut session.inputs,
+                input.as_ref(),
+                &peer,
+                net_t,
+            );
+            if let Some(input) = input.filter(|_| session.mode == "guest" && !ship_state && online)
+            {
+                session.input_log.record(input);
+            }
 
-        for ship in players.iter_mut() {
-            for bullet in ship.bullets.iter_mut() {
-                bullet.update_pos();
+            if players
+                .iter()
+                .any(|ship| ship.name() == opt.name && ship.collided())
+            {
+                sound.explosion();
             }
-        }
 
-        for asteroid in asteroids.get_asteroids().values_mut() {
-            asteroid.update_pos();
+            tick_count += 1;
+            if round_over {
+                gameover = true;
+                break;
+            }
         }
 
-        manage_collisions(
-            &mut players,
-            &mut asteroids,
-            opt.name.clone(),
-            opt.god,
-            &opt.mode,
-            frame_t,
-            sync_t,
-        );
-
-        if players
-            .iter()
-            .any(|ship| ship.name() == opt.name && ship.collided())
-        {
-            sound.explosion();
-        }
+        clear_background(LIGHTGRAY);
 
-        if asteroids.is_empty() || players.iter().all(|ship| ship.collided()) {
-            gameover = true;
+        // Guests and spectators display the entities of the host in the past,
+        // between the snapshots received.
+        let display_t = clock::now();
+        let interpolated = Some(&interpolation).filter(|_| session.mode != "host");
+
+        // Spectators watch the field through their own camera.
+        if session.mode == "spectator" {
+            let followed_pos = spectator_camera.followed().and_then(|name| {
+                interpolated
+                    .and_then(|interpolation| interpolation.ship(name, display_t))
+                    .map(|pose| pose.pos)
+                    .or_else(|| {
+                        players
+                            .iter()
+                            .find(|ship| ship.name() == name)
+                            .map(|ship| ship.pos())
+                    })
+            });
+            spectator_camera.set(followed_pos);
         }
 
-        clear_background(LIGHTGRAY);
         for ship in &players {
             for bullet in ship.bullets.iter() {
                 if !bullet.collided() {
-                    bullet.draw();
+                    draw_bullet(bullet);
                 }
             }
         }
 
-        for asteroid in asteroids.get_asteroids().values_mut() {
+        for (key, asteroid) in asteroids.get_asteroids().iter() {
             if !asteroid.collided() {
-                asteroid.draw();
+                match interpolated.and_then(|interpolation| interpolation.asteroid(key, display_t))
+                {
+                    Some(pose) => {
+                        let mut asteroid = asteroid.clone();
+                        asteroid.set_pose(pose.pos, pose.rot);
+                        draw_asteroid(&asteroid);
+                    }
+                    None => draw_asteroid(asteroid),
+                }
             }
         }
 
         for ship in &players {
             if !ship.collided() {
+                // Ships joining the round are faded while they are protected.
+                let alpha = if ship.is_protected(net_t) { 0.4 } else { 1. };
                 if ship.name() == opt.name {
-                    ship.draw(BLACK);
+                    draw_ship(ship, Color { a: alpha, ..BLACK });
                 } else {
-                    ship.draw(RED);
+                    let color = if spectator_camera.followed() == Some(ship.name().as_str()) {
+                        BLUE
+                    } else {
+                        RED
+                    };
+                    let color = Color { a: alpha, ..color };
+                    match interpolated
+                        .and_then(|interpolation| interpolation.ship(&ship.name(), display_t))
+                    {
+                        Some(pose) => {
+                            let mut ship = ship.clone();
+                            ship.set_pos(pose.pos);
+                            ship.set_rot(pose.rot);
+                            draw_ship(&ship, color);
+                        }
+                        None => draw_ship(ship, color),
+                    }
                 }
             }
         }
 
+        if session.mode == "spectator" {
+            set_default_camera();
+            spectator_camera.draw_sidebar(&players);
+        }
+
+        for ship in &players {
+            if ship.name() == opt.name {
+                display_score(ship.score());
+            }
+        }
+
         log::trace!("{} fps", get_fps());
         if show_fps {
             display_fps(&mut fps, frame_t, &mut fps_t);
+            if connection.is_some() {
+                display_net_stats(&session.stats);
+            }
+        }
+        if let Some(network_error) = &network_error {
+            display_network_error(network_error);
         }
+        session.chat.draw(clock::now());
         next_frame().await;
-        frame_count += 1;
+    }
+
+    // Let the other players know we are leaving before closing the connection.
+    if let Some(connection) = connection {
+        connection.send(opt.codec.encode(&NetMessage::Leave {
+            name: opt.name.clone(),
+        }));
+        connection.close();
     }
 }
@@ -0,0 +1,175 @@
+use macroquad::rand;
+use std::time::{Duration, Instant};
+
+/// Network conditions simulated by the network thread on the messages sent
+/// and received, to test multiplayer games on localhost.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct NetConditions {
+    /// Delay of each message, each way.
+    pub latency: Duration,
+    /// Maximum random variation of the latency.
+    pub jitter: Duration,
+    /// Probability that a message is held back and overtaken by the next ones.
+    pub reorder: f64,
+    /// Probability that a message is lost.
+    pub drop: f64,
+}
+
+/// Parse a probability given on the command line.
+pub fn parse_probability(s: &str) -> Result<f64, String> {
+    match s.parse::<f64>() {
+        Ok(p) if (0. ..=1.).contains(&p) => Ok(p),
+        _ => Err(format!("{} is not a probability between 0 and 1", s)),
+    }
+}
+
+/// One direction of the simulated network, messages are delivered once their
+/// delay is over.
+pub struct Link<T> {
+    conditions: NetConditions,
+    pending: Vec<(Instant, T)>,
+    /// Delivery time of the last message kept in order.
+    in_order_t: Option<Instant>,
+}
+
+impl<T> Link<T> {
+    /// Extra delay of a reordered message.
+    const REORDER_DELAY: Duration = Duration::from_millis(50);
+
+    pub fn new(conditions: NetConditions) -> Self {
+        Self {
+            conditions,
+            pending: Vec::new(),
+            in_order_t: None,
+        }
+    }
+
+    /// Send `msg` through the link at `now`.
+    pub fn push(&mut self, msg: T, now: Instant) {
+        let conditions = self.conditions;
+        if conditions.drop > 0. && rand::gen_range(0., 1.) < conditions.drop {
+            log::trace!("Simulating a lost message");
+            return;
+        }
+        let jitter = conditions.jitter.as_secs_f64() * rand::gen_range(-1., 1.);
+        let delay = Duration::from_secs_f64((conditions.latency.as_secs_f64() + jitter).max(0.));
+        let reordered = conditions.reorder > 0. && rand::gen_range(0., 1.) < conditions.reorder;
+        self.schedule(msg, now + delay, reordered);
+    }
+
+    fn schedule(&mut self, msg: T, mut deliver_t: Instant, reordered: bool) {
+        if reordered {
+            deliver_t += Link::<T>::REORDER_DELAY;
+        } else {
+            // WebSocket messages arrive in order, the jitter of a message
+            // delays the next ones too.
+            if let Some(in_order_t) = self.in_order_t {
+                deliver_t = deliver_t.max(in_order_t);
+            }
+            self.in_order_t = Some(deliver_t);
+        }
+        self.pending.push((deliver_t, msg));
+    }
+
+    /// Next message delivered at `now`, if any.
+    pub fn pop(&mut self, now: Instant) -> Option<T> {
+        let next = self
+            .pending
+            .iter()
+            .enumerate()
+            .filter(|(_, (deliver_t, _))| *deliver_t <= now)
+            .min_by_key(|(_, (deliver_t, _))| *deliver_t)
+            .map(|(i, _)| i)?;
+        Some(self.pending.remove(next).1)
+    }
+
+    /// All the messages not delivered yet, in delivery order.
+    pub fn flush(&mut self) -> Vec<T> {
+        self.pending.sort_by_key(|(deliver_t, _)| *deliver_t);
+        self.pending.drain(..).map(|(_, msg)| msg).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ms(ms: u64) -> Duration {
+        Duration::from_millis(ms)
+    }
+
+    #[test]
+    fn link_ideal_test() {
+        let mut link = Link::new(NetConditions::default());
+        let now = Instant::now();
+        link.push(1, now);
+        link.push(2, now);
+        assert_eq!(link.pop(now), Some(1));
+        assert_eq!(link.pop(now), Some(2));
+        assert_eq!(link.pop(now), None);
+    }
+
+    #[test]
+    fn link_latency_test() {
+        let mut link = Link::new(NetConditions {
+            latency: ms(100),
+            ..NetConditions::default()
+        });
+        let now = Instant::now();
+        link.push(1, now);
+        assert_eq!(link.pop(now + ms(99)), None);
+        assert_eq!(link.pop(now + ms(100)), Some(1));
+    }
+
+    #[test]
+    fn link_jitter_keeps_order_test() {
+        let mut link = Link::new(NetConditions::default());
+        let now = Instant::now();
+        link.schedule(1, now + ms(80), false);
+        // Sent later with less jitter, but not before the first one.
+        link.schedule(2, now + ms(30), false);
+        assert_eq!(link.pop(now + ms(50)), None);
+        assert_eq!(link.pop(now + ms(80)), Some(1));
+        assert_eq!(link.pop(now + ms(80)), Some(2));
+    }
+
+    #[test]
+    fn link_reorder_test() {
+        let mut link = Link::new(NetConditions::default());
+        let now = Instant::now();
+        link.schedule(1, now, true);
+        link.schedule(2, now, false);
+        assert_eq!(link.pop(now), Some(2));
+        assert_eq!(link.pop(now), None);
+        assert_eq!(link.pop(now + Link::<i32>::REORDER_DELAY), Some(1));
+    }
+
+    #[test]
+    fn link_drop_test() {
+        let mut link = Link::new(NetConditions {
+            drop: 1.,
+            ..NetConditions::default()
+        });
+        let now = Instant::now();
+        link.push(1, now);
+        assert_eq!(link.pop(now + ms(1000)), None);
+        assert!(link.flush().is_empty());
+    }
+
+    #[test]
+    fn link_flush_test() {
+        let mut link = Link::new(NetConditions::default());
+        let now = Instant::now();
+        link.schedule(1, now + ms(20), true);
+        link.schedule(2, now + ms(10), false);
+        assert_eq!(link.flush(), vec![2, 1]);
+        assert_eq!(link.pop(now + ms(1000)), None);
+    }
+
+    #[test]
+    fn parse_probability_test() {
+        assert_eq!(parse_probability("0.25"), Ok(0.25));
+        assert!(parse_probability("1.5").is_err());
+        assert!(parse_probability("lots").is_err());
+    }
+}
@@ -1,38 +1,164 @@
-use crate::asteroid::synchronize_asteroids;
-use crate::{asteroid::Asteroids, ship::Ship};
-use macroquad::prelude::get_time;
+use crate::clock;
+use crate::handshake::Protocol;
+#[cfg(not(target_arch = "wasm32"))]
+use crate::netsim::{Link, NetConditions};
+use crate::session::Session;
+use crate::snapshot::{GameData, Snapshots};
+use crate::MAX_ASTEROIDS;
+use macroquad::prelude::{
+    clear_background, draw_text, measure_text, next_frame, screen_height, screen_width, DARKGRAY,
+    LIGHTGRAY,
+};
+use planetoid_core::asteroid::{synchronize_asteroids, Asteroids};
+use planetoid_core::input::{Input, InputQueues};
+use planetoid_core::rng::Rng;
+use planetoid_core::ship::Ship;
+use planetoid_core::world::World;
 use serde::{Deserialize, Serialize};
-use std::sync::mpsc::{Receiver, Sender};
+#[cfg(not(target_arch = "wasm32"))]
+use std::cell::Cell;
+use std::collections::VecDeque;
+use std::str::FromStr;
+use std::sync::mpsc::TryRecvError;
+use std::{error::Error, fmt, io};
+#[cfg(not(target_arch = "wasm32"))]
 use std::{
-    error::Error,
     net::{TcpStream, ToSocketAddrs},
+    sync::mpsc::{self, Receiver, Sender},
+    thread::{self, sleep, JoinHandle},
+    time::{Duration, Instant},
 };
-use tungstenite::{client, http::Response};
-use tungstenite::{Message, WebSocket};
+#[cfg(not(target_arch = "wasm32"))]
+use tungstenite::{client, http::Response, WebSocket};
 use url::Url;
 
-type WebSocketResult<T> = Result<T, Box<dyn Error>>;
-pub fn connect_stream(url: &Url) -> TcpStream {
-    let addr = (url.host_str().unwrap(), url.port().unwrap())
-        .to_socket_addrs()
-        .unwrap()
+#[cfg(target_arch = "wasm32")]
+pub use crate::wasm_network::{Connection, Message};
+#[cfg(not(target_arch = "wasm32"))]
+pub use tungstenite::Message;
+
+#[derive(Debug)]
+pub enum NetworkError {
+    /// The url or the address of the worker is invalid.
+    Url(String),
+    Io(io::Error),
+    #[cfg(not(target_arch = "wasm32"))]
+    WebSocket(Box<tungstenite::Error>),
+    /// A message that cannot be decoded.
+    Decode(String),
+    /// The connection to the worker is closed.
+    Disconnected,
+    /// The host and this peer cannot play together.
+    Rejected(String),
+}
+
+impl NetworkError {
+    /// The server does not serve the url, like the lobby on the worker.
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn is_not_found(&self) -> bool {
+        matches!(self, NetworkError::WebSocket(e)
+            if matches!(e.as_ref(), tungstenite::Error::Http(response) if response.status() == 404))
+    }
+}
+
+impl fmt::Display for NetworkError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            NetworkError::Url(e) => write!(f, "Invalid url: {}", e),
+            NetworkError::Io(e) => write!(f, "Connection error: {}", e),
+            #[cfg(not(target_arch = "wasm32"))]
+            NetworkError::WebSocket(e) => write!(f, "WebSocket error: {}", e),
+            NetworkError::Decode(e) => write!(f, "Cannot decode message: {}", e),
+            NetworkError::Disconnected => write!(f, "Disconnected from the server"),
+            NetworkError::Rejected(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl Error for NetworkError {}
+
+impl From<io::Error> for NetworkError {
+    fn from(e: io::Error) -> Self {
+        NetworkError::Io(e)
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl From<tungstenite::Error> for NetworkError {
+    fn from(e: tungstenite::Error) -> Self {
+        match e {
+            tungstenite::Error::ConnectionClosed | tungstenite::Error::AlreadyClosed => {
+                NetworkError::Disconnected
+            }
+            e => NetworkError::WebSocket(Box::new(e)),
+        }
+    }
+}
+
+impl From<url::ParseError> for NetworkError {
+    fn from(e: url::ParseError) -> Self {
+        NetworkError::Url(e.to_string())
+    }
+}
+
+/// Events sent by the network backend to the game.
+#[derive(Debug)]
+pub enum NetworkEvent {
+    /// The connection to the worker is established or re-established.
+    Connected,
+    Message(Message),
+    /// The connection is lost, the network backend will try to reconnect.
+    Disconnected(NetworkError),
+}
+
+type NetworkResult<T> = Result<T, NetworkError>;
+
+/// Url of the game data endpoint, players of a room only receive the messages
+/// of this room. Without room, the url is the one of the worker.
+pub fn game_url(host: &str, port: u16, room: Option<&str>, name: &str) -> NetworkResult<Url> {
+    let mut url = Url::parse(&format!("ws://{}:{}/gamedata", host, port))?;
+    url.path_segments_mut()
+        .map_err(|_| NetworkError::Url(format!("Invalid host {}", host)))?
+        .extend(room)
+        .push(name);
+    Ok(url)
+}
+
+/// Url of the lobby of the relay, which lists the rooms and their players.
+#[cfg(not(target_arch = "wasm32"))]
+pub fn lobby_url(host: &str, port: u16) -> NetworkResult<Url> {
+    Ok(Url::parse(&format!("ws://{}:{}/lobby", host, port))?)
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+pub fn connect_stream(url: &Url) -> NetworkResult<TcpStream> {
+    let host = url
+        .host_str()
+        .ok_or_else(|| NetworkError::Url(format!("No host in {}", url)))?;
+    let port = url
+        .port()
+        .ok_or_else(|| NetworkError::Url(format!("No port in {}", url)))?;
+    let addr = (host, port)
+        .to_socket_addrs()?
         .last()
-        .expect("Cannot get host and port from url.");
+        .ok_or_else(|| NetworkError::Url(format!("Cannot resolve {}:{}", host, port)))?;
 
     log::debug!("Connect to TcpStream {}:{}", addr.ip(), addr.port());
-    TcpStream::connect(addr).expect("Cannot connect to specified address.")
+    Ok(TcpStream::connect(addr)?)
 }
 
+#[cfg(not(target_arch = "wasm32"))]
 pub fn connect_ws(
     url: Url,
     stream: &TcpStream,
-) -> WebSocketResult<(WebSocket<&TcpStream>, Response<()>)> {
+) -> NetworkResult<(WebSocket<&TcpStream>, Response<()>)> {
     log::debug!("Connect to WebSocket url {}", url);
-    let (socket, response) = client(url, stream).expect("Cannot connect to specified url.");
+    let (socket, response) = client(url, stream).map_err(|e| match e {
+        tungstenite::HandshakeError::Failure(e) => NetworkError::from(e),
+        tungstenite::HandshakeError::Interrupted(_) => NetworkError::Disconnected,
+    })?;
 
-    stream
-        .set_nonblocking(true)
-        .expect("set_nonblocking call failed");
+    stream.set_nonblocking(true)?;
 
     log::info!("Connected to the server");
     log::info!("Response HTTP code: {}", response.status());
@@ -40,133 +166,1670 @@ pub fn connect_ws(
     Ok((socket, response))
 }
 
-#[derive(Serialize, Deserialize)]
-struct GameData {
+/// Connection to the worker, managed by a network thread.
+#[cfg(not(target_arch = "wasm32"))]
+pub struct Connection {
+    tx_to_socket: Sender<Message>,
+    rx_from_socket: Receiver<NetworkEvent>,
+    network_thread: JoinHandle<()>,
+    /// Messages and bytes sent.
+    sent: Cell<(u64, u64)>,
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl Connection {
+    /// Open a connection degraded by the simulated network `conditions`, the
+    /// default conditions do not change anything.
+    pub fn open_simulated(url: Url, conditions: NetConditions) -> Self {
+        let (tx_from_socket, rx_from_socket) = mpsc::channel();
+        let (tx_to_socket, rx_to_socket) = mpsc::channel();
+
+        // Thread to manage network web socket
+        // This thread uses a channel to pass messages to the main thread (game)
+        let network_thread = thread::spawn(move || {
+            run_socket(url, conditions, rx_to_socket, tx_from_socket);
+        });
+
+        Self {
+            tx_to_socket,
+            rx_from_socket,
+            network_thread,
+            sent: Cell::new((0, 0)),
+        }
+    }
+
+    /// Send errors are not returned, a lost connection is reported by
+    /// `try_recv` as a Disconnected event.
+    pub fn send(&self, msg: Message) {
+        let (count, bytes) = self.sent.get();
+        self.sent.set((count + 1, bytes + msg.len() as u64));
+        let _ = self.tx_to_socket.send(msg);
+    }
+
+    /// Messages and bytes sent since the connection was opened.
+    pub fn sent(&self) -> (u64, u64) {
+        self.sent.get()
+    }
+
+    pub fn try_recv(&mut self) -> Result<NetworkEvent, TryRecvError> {
+        self.rx_from_socket.try_recv()
+    }
+
+    /// Close the connection once the pending messages are sent.
+    pub fn close(self) {
+        drop(self.tx_to_socket);
+        if self.network_thread.join().is_err() {
+            log::error!("Network thread panicked.");
+        }
+    }
+}
+
+/// Network thread main loop.
+/// Pass messages from the game to the web socket and from the web socket to the game
+/// until the game drops its sender. If the connection is lost, retry with an exponential
+/// backoff.
+/// Messages in both directions go through the simulated network `conditions`.
+#[cfg(not(target_arch = "wasm32"))]
+pub fn run_socket(
+    url: Url,
+    conditions: NetConditions,
+    rx_to_socket: Receiver<Message>,
+    tx_from_socket: Sender<NetworkEvent>,
+) {
+    if conditions != NetConditions::default() {
+        log::info!("Simulating network conditions {:?}", conditions);
+    }
+    let mut retry_delay = RETRY_DELAY_MIN;
+    loop {
+        match socket_loop(
+            &url,
+            conditions,
+            &rx_to_socket,
+            &tx_from_socket,
+            &mut retry_delay,
+        ) {
+            Ok(()) => return,
+            Err(e) => {
+                log::error!("{}", e);
+                if tx_from_socket.send(NetworkEvent::Disconnected(e)).is_err() {
+                    return;
+                }
+            }
+        }
+
+        log::info!("Reconnecting in {:?}.", retry_delay);
+        if !wait_retry(retry_delay, &rx_to_socket) {
+            return;
+        }
+        retry_delay = (retry_delay * 2).min(RETRY_DELAY_MAX);
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+const RETRY_DELAY_MIN: Duration = Duration::from_millis(500);
+#[cfg(not(target_arch = "wasm32"))]
+const RETRY_DELAY_MAX: Duration = Duration::from_secs(8);
+
+/// Wait before reconnecting, messages sent by the game meanwhile are dropped.
+/// Return false if the game is exiting.
+#[cfg(not(target_arch = "wasm32"))]
+fn wait_retry(delay: Duration, rx_to_socket: &Receiver<Message>) -> bool {
+    let start = Instant::now();
+    while start.elapsed() < delay {
+        loop {
+            match rx_to_socket.try_recv() {
+                Ok(_) => (),
+                Err(mpsc::TryRecvError::Empty) => break,
+                Err(mpsc::TryRecvError::Disconnected) => return false,
+            }
+        }
+        sleep(Duration::from_millis(50));
+    }
+    true
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn socket_loop(
+    url: &Url,
+    conditions: NetConditions,
+    rx_to_socket: &Receiver<Message>,
+    tx_from_socket: &Sender<NetworkEvent>,
+    retry_delay: &mut Duration,
+) -> NetworkResult<()> {
+    let stream = connect_stream(url)?;
+    let (mut socket, _response) = connect_ws(url.clone(), &stream)?;
+    *retry_delay = RETRY_DELAY_MIN;
+    if tx_from_socket.send(NetworkEvent::Connected).is_err() {
+        return Ok(());
+    }
+    // Messages in flight are lost with the connection.
+    let mut outgoing = Link::new(conditions);
+    let mut incoming = Link::new(conditions);
+    loop {
+        match rx_to_socket.try_recv() {
+            Ok(msg) => outgoing.push(msg, Instant::now()),
+            Err(mpsc::TryRecvError::Empty) => (),
+            Err(mpsc::TryRecvError::Disconnected) => {
+                // The game is exiting, write the pending messages (like Leave)
+                // without waiting for their simulated delay.
+                log::info!("Closing connection.");
+                for msg in outgoing.flush() {
+                    socket.write_message(msg)?;
+                }
+                let _ = socket.close(None);
+                return Ok(());
+            }
+        };
+        while let Some(msg) = outgoing.pop(Instant::now()) {
+            socket.write_message(msg)?;
+        }
+
+        match socket.read_message() {
+            Ok(msg) if msg.is_text() || msg.is_binary() => incoming.push(msg, Instant::now()),
+            // Control frames are answered by tungstenite.
+            Ok(_) => (),
+            Err(tungstenite::Error::Io(e)) if e.kind() == io::ErrorKind::WouldBlock => (),
+            Err(e) => return Err(e.into()),
+        }
+        while let Some(msg) = incoming.pop(Instant::now()) {
+            if tx_from_socket.send(NetworkEvent::Message(msg)).is_err() {
+                return Ok(());
+            }
+        }
+        sleep(Duration::from_millis(5));
+    }
+}
+
+/// Messages exchanged between the host, guests and spectators through the worker.
+/// Serialized as `{"type": "<variant>", "data": <payload>}`.
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(tag = "type", content = "data")]
+pub enum NetMessage {
+    /// Sent by a guest or a spectator joining the game.
+    Hello {
+        name: String,
+        #[serde(default)]
+        protocol: Protocol,
+        #[serde(default)]
+        spectator: bool,
+    },
+    /// Answer of the host to a compatible hello. On `gameover`, the player
+    /// joins the next round. `seed` is the seed of the simulation of the
+    /// host.
+    Welcome {
+        name: String,
+        protocol: Protocol,
+        #[serde(default)]
+        gameover: bool,
+        #[serde(default)]
+        seed: Option<u64>,
+    },
+    /// Answer of the host to an incompatible hello.
+    Reject {
+        name: String,
+        reason: String,
+    },
+    GameData(GameData),
+    /// Whole ship of a guest, kept as a fallback for guests that do not send
+    /// their inputs.
+    GuestData(GuestData),
+    /// Inputs of a guest not sent yet, its ship is simulated by the host.
+    Inputs {
+        name: String,
+        inputs: Vec<Input>,
+    },
+    /// Last snapshot applied by a guest or a spectator, 0 to request a keyframe.
+    Ack {
+        name: String,
+        id: u32,
+    },
+    Leave {
+        name: String,
+    },
+    GameOver,
+    /// Sent by the host while it does not send snapshots, so peers know it is
+    /// still there.
+    Heartbeat,
+    /// Clock of a guest or a spectator when it sends the ping.
+    Ping {
+        name: String,
+        t: f64,
+    },
+    /// Answer of the host to a ping: `t0` is the clock of the peer when it sent
+    /// the ping, `t1` and `t2` the clock of the host when it received the ping
+    /// and when it answered.
+    Pong {
+        name: String,
+        t0: f64,
+        t1: f64,
+        t2: f64,
+    },
+    /// Text of the chat, broadcast to every player of the room.
+    Chat {
+        name: String,
+        text: String,
+    },
+}
+
+impl NetMessage {
+    /// Name of the peer sending the message, `None` for the messages only sent
+    /// by the host.
+    fn sender(&self) -> Option<String> {
+        match self {
+            NetMessage::Hello { name, .. }
+            | NetMessage::Inputs { name, .. }
+            | NetMessage::Ack { name, .. }
+            | NetMessage::Leave { name }
+            | NetMessage::Ping { name, .. }
+            | NetMessage::Chat { name, .. } => Some(name.clone()),
+            NetMessage::GameData(gamedata) => Some(gamedata.host().to_string()),
+            NetMessage::GuestData(guestdata) => Some(guestdata.ship.name()),
+            NetMessage::Welcome { .. }
+            | NetMessage::Reject { .. }
+            | NetMessage::GameOver
+            | NetMessage::Heartbeat
+            | NetMessage::Pong { .. } => None,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct GuestData {
     asteroids: Asteroids,
-    players: Vec<Ship>,
-    gameover: bool,
+    ship: Ship,
+}
+
+/// Wire encoding of the messages.
+/// Each peer can use its own codec as messages are decoded according to the frame type.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Codec {
+    /// JSON over WebSocket text frames.
+    Json,
+    /// MessagePack over WebSocket binary frames.
+    MessagePack,
+}
+
+impl Codec {
+    pub const VALUES: &'static [&'static str] = &["json", "msgpack"];
+
+    pub fn encode(&self, msg: &NetMessage) -> Message {
+        match self {
+            Codec::Json => Message::Text(serde_json::to_string(msg).unwrap()),
+            // Structs are encoded as maps because the hand-written deserializers expect them.
+            Codec::MessagePack => Message::Binary(rmp_serde::to_vec_named(msg).unwrap()),
+        }
+    }
+
+    pub fn decode(msg: &Message) -> NetworkResult<NetMessage> {
+        match msg {
+            Message::Text(text) => {
+                serde_json::from_str(text).map_err(|e| NetworkError::Decode(e.to_string()))
+            }
+            Message::Binary(data) => {
+                rmp_serde::from_slice(data).map_err(|e| NetworkError::Decode(e.to_string()))
+            }
+            // Browser messages are only text or binary.
+            #[allow(unreachable_patterns)]
+            _ => Err(NetworkError::Decode(format!("Unexpected frame {:?}", msg))),
+        }
+    }
+}
+
+impl FromStr for Codec {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "json" => Ok(Codec::Json),
+            "msgpack" => Ok(Codec::MessagePack),
+            _ => Err(format!("Unknown codec {}", s)),
+        }
+    }
+}
+
+/// Estimation of the clock of the host from ping/pong exchanges, like NTP.
+///
+/// Times shared between peers (`Asteroid::last_updated`, `Bullet::shot_at`)
+/// are given by `now`, which is the clock of the host. The host answers the
+/// pings with its own `now`, so a guest taking over keeps the same time.
+pub struct ClockSync {
+    /// Round trip time and offset of the last exchanges.
+    samples: VecDeque<(f64, f64)>,
+    /// Difference between the clock of the host and the local clock.
+    offset: f64,
+    /// Round trip time to the host.
+    rtt: f64,
+    ping_t: Option<f64>,
+    /// Pings received by the host with the time they were received.
+    pings: Vec<(String, f64, f64)>,
+}
+
+impl ClockSync {
+    const SAMPLES: usize = 8;
+    const PING_INTERVAL: f64 = 1.;
+    /// Interval until enough samples are received after joining.
+    const FIRST_PING_INTERVAL: f64 = 0.2;
+
+    pub fn new() -> Self {
+        Self {
+            samples: VecDeque::new(),
+            offset: 0.,
+            rtt: 0.,
+            ping_t: None,
+            pings: Vec::new(),
+        }
+    }
+
+    /// Time of the host.
+    pub fn now(&self) -> f64 {
+        clock::now() + self.offset
+    }
+
+    /// Return the ping to send to the host if it is time to.
+    pub fn ping(&mut self, name: &str) -> Option<NetMessage> {
+        let t = clock::now();
+        let interval = if self.samples.len() < ClockSync::SAMPLES {
+            ClockSync::FIRST_PING_INTERVAL
+        } else {
+            ClockSync::PING_INTERVAL
+        };
+        if self.ping_t.is_some_and(|ping_t| t - ping_t < interval) {
+            return None;
+        }
+        self.ping_t = Some(t);
+        Some(NetMessage::Ping {
+            name: name.to_string(),
+            t,
+        })
+    }
+
+    /// Host side, record a ping to answer.
+    pub fn ping_received(&mut self, name: String, t0: f64) {
+        self.pings.push((name, t0, self.now()));
+    }
+
+    /// Host side, answers to the pings received since the last call.
+    pub fn pongs(&mut self) -> Vec<NetMessage> {
+        let t2 = self.now();
+        self.pings
+            .drain(..)
+            .map(|(name, t0, t1)| NetMessage::Pong { name, t0, t1, t2 })
+            .collect()
+    }
+
+    /// Guest side, update the estimation with a pong received at `t3` and
+    /// return the round trip time of this exchange.
+    /// The sample with the shortest round trip is the least disturbed by the
+    /// network, so its offset is used.
+    pub fn pong_received(&mut self, t0: f64, t1: f64, t2: f64, t3: f64) -> f64 {
+        let rtt = (t3 - t0) - (t2 - t1);
+        let offset = ((t1 - t0) + (t2 - t3)) / 2.;
+        self.samples.push_back((rtt, offset));
+        if self.samples.len() > ClockSync::SAMPLES {
+            self.samples.pop_front();
+        }
+        (self.rtt, self.offset) = self
+            .samples
+            .iter()
+            .copied()
+            .min_by(|(rtt1, _), (rtt2, _)| rtt1.total_cmp(rtt2))
+            .unwrap();
+        log::debug!("Clock offset {:.3}s, rtt {:.3}s", self.offset, self.rtt);
+        rtt
+    }
+}
+
+/// The relay and the worker broadcast their own notices like "User xxx joined"
+/// in plain text, they are not malformed messages.
+fn is_notice(text: &str) -> bool {
+    text.starts_with("User ") && (text.ends_with(" joined") || text.contains(" left on error: "))
 }
 
-#[allow(clippy::too_many_arguments)]
 pub fn deserialize_host_data(
-    name: &str,
-    mode: &str,
+    session: &mut Session,
     msg: Message,
     asteroids: &mut Asteroids,
     players: &mut Vec<Ship>,
     gameover: &mut bool,
-    host_msg_received: &mut bool,
-    sync_t: &mut f64,
-) {
-    if let Message::Text(msg) = msg {
-        log::debug!("{}", msg);
-        if msg.contains("Hello from ") {
-            let name = msg.strip_prefix("Hello from ").unwrap();
-            players.push(Ship::new(String::from(name)));
-            *sync_t = get_time();
-            asteroids.refresh_last_updated(get_time() - *sync_t);
-        }
-
-        if mode == "host" && msg.contains("GuestData: ") {
-            let msg = msg.strip_prefix("GuestData: ").unwrap();
-            let guestdata: GuestData = serde_json::from_str(msg).unwrap();
-            let opponent = guestdata.ship;
-            for ship in players.iter_mut() {
-                if ship.name() == opponent.name() {
-                    *ship = opponent.clone();
-                }
+    world: &mut World,
+) -> NetworkResult<()> {
+    let Session {
+        name,
+        mode,
+        host_msg_received,
+        clock_sync,
+        snapshots,
+        roster,
+        handshake,
+        inputs,
+        stats,
+        chat,
+        ..
+    } = session;
+    let (name, mode) = (name.as_str(), mode.as_str());
+    match &msg {
+        Message::Text(text) => log::debug!("{}", text),
+        msg => log::debug!("{} bytes message", msg.len()),
+    }
+    if let Message::Text(text) = &msg {
+        if is_notice(text) {
+            log::debug!("Ignoring notice: {}", text);
+            return Ok(());
+        }
+    }
+    let bytes = msg.len();
+    let msg = Codec::decode(&msg).inspect_err(|_| stats.received(None, bytes))?;
+    // Messages only sent by the host are counted for the current host.
+    let sender = msg
+        .sender()
+        .or_else(|| roster.host().map(String::from))
+        .unwrap_or_default();
+    stats.received(Some(&sender), bytes);
+    if mode == "host" && handshake.is_rejected(&sender) && !matches!(msg, NetMessage::Hello { .. })
+    {
+        return Ok(());
+    }
+
+    match msg {
+        NetMessage::Hello {
+            name,
+            protocol,
+            spectator,
+        } if mode == "host" => {
+            if !handshake.hello_received(&name, &protocol, *gameover) {
+                return Ok(());
+            }
+            roster.seen(&name, clock::now());
+            // A guest says hello again when it reconnects and keeps its
+            // ship. A guest joining a running round spawns away from the
+            // asteroids, on the gameover screen its ship is added by the
+            // first inputs of the next round. The others get the ship
+            // with the snapshots of the host.
+            if !spectator && !*gameover && !players.iter().any(|ship| ship.name() == name) {
+                players.push(Ship::spawn(
+                    name.clone(),
+                    asteroids.safe_position(world),
+                    clock_sync.now(),
+                ));
+                asteroids.refresh_last_updated(clock_sync.now());
             }
-            synchronize_asteroids(asteroids, guestdata.asteroids);
         }
 
-        if mode != "host" && msg.contains("GameData: ") {
-            let msg = msg.strip_prefix("GameData: ").unwrap();
+        NetMessage::Welcome {
+            name: to,
+            protocol,
+            gameover,
+            seed,
+        } if mode != "host" && to == name => {
+            handshake.welcome_received(protocol, gameover, seed);
+        }
+
+        NetMessage::Reject { name: to, reason } if mode != "host" && to == name => {
+            handshake.reject_received(reason);
+        }
 
-            // Backup player ship
-            let mut current_ship: Ship = Ship::new(name.to_string());
-            for ship in players.clone() {
-                if ship.name() == name {
-                    current_ship = ship;
+        NetMessage::GuestData(guestdata) if mode == "host" => {
+            let opponent = guestdata.ship;
+            roster.seen(&opponent.name(), clock::now());
+            // Do not trust the guests sending their inputs with their ship.
+            if !handshake.sends_ship_state(&opponent.name()) {
+                log::debug!("Ignoring the ship sent by {}", opponent.name());
+                return Ok(());
+            }
+            inputs.remove(&opponent.name());
+            match players
+                .iter_mut()
+                .find(|ship| ship.name() == opponent.name())
+            {
+                Some(ship) => *ship = opponent,
+                None => {
+                    // The guest was dropped after a timeout but is back.
+                    log::info!("{} is back", opponent.name());
+                    players.push(opponent);
                 }
             }
+            synchronize_asteroids(asteroids, guestdata.asteroids);
+        }
 
-            let gamedata: GameData = serde_json::from_str(msg).unwrap();
-            synchronize_asteroids(asteroids, gamedata.asteroids);
-            *gameover = gamedata.gameover;
-            *players = gamedata.players;
+        NetMessage::Inputs {
+            name,
+            inputs: guest_inputs,
+        } if mode == "host" => {
+            roster.seen(&name, clock::now());
+            if !players.iter().any(|ship| ship.name() == name) {
+                // The guest was dropped after a timeout but is back, or
+                // starts the next round.
+                log::info!("{} is back", name);
+                players.push(Ship::spawn(
+                    name.clone(),
+                    asteroids.safe_position(world),
+                    clock_sync.now(),
+                ));
+            }
+            inputs.push(&name, guest_inputs);
+        }
 
-            // Restore current ship
-            for ship in players {
-                if ship.name() == name {
-                    *ship = current_ship.clone();
+        NetMessage::GameData(mut gamedata) if mode != "host" => {
+            // Even if the snapshot cannot be applied, the acknowledgement will
+            // request a keyframe.
+            let host = gamedata.host().to_string();
+            stats.snapshot_received(&host, gamedata.id());
+            let rng = gamedata.take_rng();
+            if snapshots.apply(gamedata, name, asteroids, players) {
+                roster.update_members(&host, players, clock::now());
+                // Continue the random sequence of the host, which went on
+                // since the seed with the fields, splits and spawns.
+                if let Some(rng) = rng {
+                    world.rng = rng;
                 }
             }
             *host_msg_received = true;
         }
+
+        NetMessage::Ack { name, id } if mode == "host" => {
+            roster.seen(&name, clock::now());
+            if let Some(rtt) = snapshots.ack(&name, id) {
+                stats.rtt(&name, rtt);
+            }
+        }
+
+        NetMessage::Leave { name } => {
+            log::info!("{} left the game", name);
+            players.retain(|ship| ship.name() != name);
+            snapshots.remove_peer(&name);
+            roster.remove(&name);
+            inputs.remove(&name);
+            stats.remove(&name);
+        }
+
+        NetMessage::GameOver if mode != "host" => {
+            roster.host_seen(clock::now());
+            *gameover = true;
+        }
+
+        NetMessage::Heartbeat if mode != "host" => {
+            roster.host_seen(clock::now());
+        }
+
+        NetMessage::Ping { name, t } if mode == "host" => {
+            clock_sync.ping_received(name, t);
+        }
+
+        NetMessage::Pong {
+            name: to,
+            t0,
+            t1,
+            t2,
+        } if mode != "host" && to == name => {
+            let rtt = clock_sync.pong_received(t0, t1, t2, clock::now());
+            stats.rtt(&sender, rtt);
+        }
+
+        NetMessage::Chat { name, text } => {
+            chat.received(name, &text, clock::now());
+        }
+
+        _ => (),
     }
+    Ok(())
 }
 
 pub fn serialize_host_data(
+    codec: Codec,
+    name: &str,
+    snapshots: &mut Snapshots,
     asteroids: &mut Asteroids,
     players: &mut [Ship],
-    gameover: &mut bool,
-) -> String {
-    let gamedata = GameData {
-        asteroids: asteroids.clone(),
-        players: players.to_vec(),
-        gameover: *gameover,
-    };
-
-    format!("GameData: {}", serde_json::to_string(&gamedata).unwrap())
-}
+    inputs: &InputQueues,
+    world: &World,
+) -> Message {
+    let mut gamedata = snapshots.next(name, asteroids, players);
+    gamedata.set_inputs(inputs.applied());
+    if gamedata.is_keyframe() {
+        gamedata.set_rng(world.rng.clone());
+    }
 
-#[derive(Serialize, Deserialize)]
-struct GuestData {
-    asteroids: Asteroids,
-    ship: Ship,
+    codec.encode(&NetMessage::GameData(gamedata))
 }
 
-pub fn serialize_guest_data(ship: &Ship, asteroids: &mut Asteroids) -> String {
+pub fn serialize_guest_data(codec: Codec, ship: &Ship, asteroids: &mut Asteroids) -> Message {
     let guestdata = GuestData {
         asteroids: asteroids.clone(),
         ship: ship.clone(),
     };
-    format!("GuestData: {}", serde_json::to_string(&guestdata).unwrap())
+    codec.encode(&NetMessage::GuestData(guestdata))
 }
 
-#[allow(clippy::too_many_arguments)]
-pub fn wait_synchronization_data(
-    rx_from_socket: &Receiver<Message>,
-    tx_to_socket: &Sender<String>,
-    name: &str,
-    mode: &str,
+pub fn serialize_guest_inputs(codec: Codec, name: &str, inputs: Vec<Input>) -> Message {
+    codec.encode(&NetMessage::Inputs {
+        name: name.to_string(),
+        inputs,
+    })
+}
+
+/// Forget the lost host, the oldest remaining player takes over the game with
+/// its last state.
+pub fn migrate_host(
+    lost_host: &str,
+    session: &mut Session,
     asteroids: &mut Asteroids,
     players: &mut Vec<Ship>,
-    gameover: &mut bool,
-    host_msg_received: &mut bool,
-    sync_t: &mut f64,
+    world: &mut World,
 ) {
-    if mode != "host" {
+    log::info!("Host {} lost", lost_host);
+    players.retain(|ship| ship.name() != lost_host);
+    match session.roster.host() {
+        Some(host) if host == session.name && session.mode == "guest" => {
+            log::info!("{} is the new host", session.name);
+            session.mode = String::from("host");
+            session.snapshots.promote();
+            session.roster.clear();
+            // The host was lost on the gameover screen, start a new game.
+            if asteroids.is_empty() {
+                *asteroids = Asteroids::generate_field(session.name.clone(), MAX_ASTEROIDS, world);
+                session.snapshots.new_round(session.clock_sync.now());
+            }
+        }
+        Some(host) => {
+            log::info!("{} is the new host", host);
+            // The new host learns the protocol of this peer, and whether it
+            // sends its whole ship, from a new hello.
+            session.handshake.reset();
+        }
+        None => log::info!("No player left to host the game"),
+    }
+}
+
+/// Wait for the game state of the host, the game screen is replaced by a
+/// waiting message meanwhile.
+pub async fn wait_synchronization_data(
+    connection: &mut Connection,
+    session: &mut Session,
+    asteroids: &mut Asteroids,
+    players: &mut Vec<Ship>,
+    gameover: &mut bool,
+    world: &mut World,
+) -> NetworkResult<()> {
+    if session.mode != "host" {
         log::info!("Waiting synchronization data");
+        session.handshake.reset();
+        // Let the host know we need a keyframe.
+        connection.send(session.codec.encode(&NetMessage::Ack {
+            name: session.name.clone(),
+            id: session.snapshots.applied(),
+        }));
         loop {
-            let msg = rx_from_socket.recv().unwrap();
+            let msg = match connection.try_recv() {
+                Ok(NetworkEvent::Message(msg)) => msg,
+                Ok(NetworkEvent::Connected) => continue,
+                Ok(NetworkEvent::Disconnected(e)) => return Err(e),
+                Err(TryRecvError::Empty) => {
+                    if let Some(hello) = session.handshake.hello(
+                        &session.name,
+                        session.mode == "spectator",
+                        clock::now(),
+                    ) {
+                        connection.send(session.codec.encode(&hello));
+                    }
+                    if let Some(lost_host) = session.roster.host_lost(clock::now()) {
+                        migrate_host(&lost_host, session, asteroids, players, world);
+                        if session.mode == "host" {
+                            return Ok(());
+                        }
+                    }
+                    if let Some(msg) = session.chat.update(&session.name) {
+                        connection.send(session.codec.encode(&msg));
+                    }
+                    display_waiting_host(session.handshake.waits_next_round());
+                    session.chat.draw(clock::now());
+                    next_frame().await;
+                    continue;
+                }
+                Err(TryRecvError::Disconnected) => return Err(NetworkError::Disconnected),
+            };
+            if let Err(e) = deserialize_host_data(session, msg, asteroids, players, gameover, world)
+            {
+                log::warn!("Dropping message: {}", e);
+            }
+            if let Some(rejection) = session.handshake.rejection() {
+                return Err(NetworkError::Rejected(rejection.to_string()));
+            }
+            if session.handshake.welcomed() && !asteroids.is_empty() {
+                // The keyframe brought the random generator of the host,
+                // the builds without it only give their seed.
+                if world.rng.seed() != session.handshake.seed() {
+                    log::info!("Simulation seed of the host: {}", session.handshake.seed());
+                    world.rng = Rng::new(session.handshake.seed());
+                }
+                let round = session.snapshots.round();
+                log::info!(
+                    "Joining round {} started {:.0}s ago",
+                    round.number,
+                    session.clock_sync.now() - round.started_t
+                );
+                break;
+            }
+        }
+    }
+    Ok(())
+}
+
+fn display_waiting_host(next_round: bool) {
+    clear_background(LIGHTGRAY);
+    let text = if next_round {
+        "Waiting for the next round..."
+    } else {
+        "Waiting for the host..."
+    };
+    let font_size = 30.;
+    let text_size = measure_text(text, None, font_size as _, 1.0);
+    draw_text(
+        text,
+        screen_width() / 2. - text_size.width / 2.,
+        screen_height() / 2. - text_size.height / 2.,
+        font_size,
+        DARKGRAY,
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::screen;
+    use macroquad::prelude::Vec2;
+    use planetoid_core::asteroid::Asteroid;
+    use planetoid_core::bullet::Bullet;
+    use std::net::TcpListener;
+    use std::thread;
+
+    fn field() -> Asteroids {
+        let mut asteroids = Asteroids::default();
+        asteroids.add_asteroid(String::from("f1"), Asteroid::new_pos_and_size(1., 2., 10.));
+        asteroids.add_asteroid(String::from("f1"), Asteroid::new_pos_and_size(3., 4., 20.));
+        asteroids
+    }
+
+    fn ship(name: &str) -> Ship {
+        let mut ship = Ship::new_pos(String::from(name), Vec2::new(10., 20.));
+        ship.bullets
+            .push(Bullet::new(Vec2::new(1., 1.), Vec2::new(2., 2.), 5., false));
+        ship
+    }
+
+    /// Game of a peer receiving the messages of the others.
+    struct PeerState {
+        session: Session,
+        asteroids: Asteroids,
+        players: Vec<Ship>,
+        gameover: bool,
+        world: World,
+    }
+
+    impl PeerState {
+        fn receive(&mut self, msg: Message) -> NetworkResult<()> {
             deserialize_host_data(
-                name,
-                mode,
+                &mut self.session,
                 msg,
-                asteroids,
-                players,
-                gameover,
-                host_msg_received,
-                sync_t,
+                &mut self.asteroids,
+                &mut self.players,
+                &mut self.gameover,
+                &mut self.world,
+            )
+        }
+
+        fn receive_msg(&mut self, msg: NetMessage) {
+            self.receive(Codec::Json.encode(&msg)).unwrap();
+        }
+    }
+
+    /// `name` plays in `mode` on the field of the host Uggla.
+    fn fixture(name: &str, mode: &str) -> PeerState {
+        PeerState {
+            session: Session::new(name, mode, Codec::Json, 5., 42),
+            asteroids: field(),
+            players: vec![ship("Uggla")],
+            gameover: false,
+            world: World::new(screen::WIDTH, screen::HEIGHT, 42),
+        }
+    }
+
+    const CODECS: &[Codec] = &[Codec::Json, Codec::MessagePack];
+
+    fn round_trip(codec: Codec, msg: NetMessage) -> NetMessage {
+        let serialize = codec.encode(&msg);
+        dbg!(&serialize);
+        let deserialize = Codec::decode(&serialize).unwrap();
+        let serialize2 = codec.encode(&deserialize);
+        assert_eq!(serialize, serialize2);
+        deserialize
+    }
+
+    #[test]
+    fn message_hello_round_trip_test() {
+        for codec in CODECS {
+            let msg = NetMessage::Hello {
+                name: String::from("Uggla"),
+                protocol: Protocol::current(),
+                spectator: true,
+            };
+            match round_trip(*codec, msg) {
+                NetMessage::Hello {
+                    name,
+                    protocol,
+                    spectator,
+                } => {
+                    assert_eq!(name, "Uggla");
+                    assert_eq!(protocol, Protocol::current());
+                    assert!(spectator);
+                }
+                msg => panic!("Unexpected message {:?}", msg),
+            }
+        }
+    }
+
+    #[test]
+    fn message_hello_compatibility_test() {
+        // Hello of the builds without handshake.
+        let msg = Message::Text(String::from(r#"{"type":"Hello","data":{"name":"Old"}}"#));
+        assert!(matches!(
+            Codec::decode(&msg).unwrap(),
+            NetMessage::Hello { protocol, spectator: false, .. } if protocol.version == 0
+        ));
+
+        // Optional fields of newer builds are ignored.
+        let msg = Message::Text(String::from(
+            r#"{"type":"Hello","data":{"name":"New","protocol":{"version":2,"capabilities":["inputs","teleport"]},"spectator":false,"color":"red"}}"#,
+        ));
+        assert!(matches!(
+            Codec::decode(&msg).unwrap(),
+            NetMessage::Hello { protocol, .. } if protocol.check().is_ok() && protocol.supports("teleport")
+        ));
+    }
+
+    #[test]
+    fn message_gamedata_round_trip_test() {
+        for codec in CODECS {
+            let mut host = Snapshots::new();
+            let players = vec![ship("Uggla"), ship("Guest")];
+            let gamedata = host.next("Uggla", &field(), &players);
+            match round_trip(*codec, NetMessage::GameData(gamedata)) {
+                NetMessage::GameData(gamedata) => {
+                    let mut asteroids = Asteroids::default();
+                    let mut guest_players = Vec::new();
+                    let mut guest = Snapshots::new();
+                    assert_eq!(gamedata.host(), "Uggla");
+                    assert!(gamedata.is_keyframe());
+                    assert!(guest.apply(gamedata, "Guest", &mut asteroids, &mut guest_players));
+                    assert_eq!(asteroids.get_asteroids().len(), 2);
+                    assert_eq!(guest_players, players);
+                }
+                msg => panic!("Unexpected message {:?}", msg),
+            }
+        }
+    }
+
+    #[test]
+    fn message_guestdata_round_trip_test() {
+        for codec in CODECS {
+            let guestdata = GuestData {
+                asteroids: field(),
+                ship: ship("Guest"),
+            };
+            match round_trip(*codec, NetMessage::GuestData(guestdata)) {
+                NetMessage::GuestData(mut guestdata) => {
+                    assert_eq!(guestdata.asteroids.get_asteroids().len(), 2);
+                    assert_eq!(guestdata.ship.name(), "Guest");
+                }
+                msg => panic!("Unexpected message {:?}", msg),
+            }
+        }
+    }
+
+    #[test]
+    fn message_inputs_round_trip_test() {
+        for codec in CODECS {
+            let msg = NetMessage::Inputs {
+                name: String::from("Guest"),
+                inputs: vec![Input::new(true, -1, true, 1.5)],
+            };
+            match round_trip(*codec, msg) {
+                NetMessage::Inputs { name, inputs } => {
+                    assert_eq!(name, "Guest");
+                    assert_eq!(inputs, vec![Input::new(true, -1, true, 1.5)]);
+                }
+                msg => panic!("Unexpected message {:?}", msg),
+            }
+        }
+    }
+
+    #[test]
+    fn message_ping_pong_round_trip_test() {
+        for codec in CODECS {
+            let msg = NetMessage::Ping {
+                name: String::from("Guest"),
+                t: 1.5,
+            };
+            assert!(
+                matches!(round_trip(*codec, msg), NetMessage::Ping { name, t } if name == "Guest" && t == 1.5)
             );
-            if !asteroids.is_empty() {
-                break;
+            let msg = NetMessage::Pong {
+                name: String::from("Guest"),
+                t0: 1.5,
+                t1: 10.,
+                t2: 10.5,
+            };
+            assert!(matches!(
+                round_trip(*codec, msg),
+                NetMessage::Pong { name, t0, t1, t2 } if name == "Guest" && t0 == 1.5 && t1 == 10. && t2 == 10.5
+            ));
+        }
+    }
+
+    #[test]
+    fn clock_sync_test() {
+        let mut clock_sync = ClockSync::new();
+        // The host clock is 100s ahead, the network takes 0.1s each way.
+        assert!((clock_sync.pong_received(1., 101.1, 101.2, 1.3) - 0.2).abs() < 1e-9);
+        assert!((clock_sync.offset - 100.).abs() < 1e-9);
+        assert!((clock_sync.rtt - 0.2).abs() < 1e-9);
+
+        // A late pong gives a wrong offset, the fastest exchange is kept.
+        clock_sync.pong_received(2., 102.1, 102.1, 2.6);
+        assert!((clock_sync.offset - 100.).abs() < 1e-9);
+        assert!((clock_sync.now() - clock::now() - 100.).abs() < 1e-3);
+    }
+
+    #[test]
+    fn clock_sync_ping_test() {
+        let mut guest = ClockSync::new();
+        assert!(guest.ping("Guest").is_some());
+        // Too soon for another ping.
+        assert!(guest.ping("Guest").is_none());
+
+        let mut host = ClockSync::new();
+        host.ping_received(String::from("Guest"), 1.);
+        host.ping_received(String::from("Spectator"), 2.);
+        let pongs = host.pongs();
+        assert_eq!(pongs.len(), 2);
+        assert!(
+            matches!(&pongs[1], NetMessage::Pong { name, t0, .. } if name == "Spectator" && *t0 == 2.)
+        );
+        assert!(host.pongs().is_empty());
+    }
+
+    #[test]
+    fn message_ack_round_trip_test() {
+        for codec in CODECS {
+            let msg = NetMessage::Ack {
+                name: String::from("Guest"),
+                id: 42,
+            };
+            match round_trip(*codec, msg) {
+                NetMessage::Ack { name, id } => {
+                    assert_eq!(name, "Guest");
+                    assert_eq!(id, 42);
+                }
+                msg => panic!("Unexpected message {:?}", msg),
             }
         }
+    }
 
-        if mode == "guest" {
-            tx_to_socket.send(format!("Hello from {}", name)).unwrap();
+    #[test]
+    fn message_leave_round_trip_test() {
+        for codec in CODECS {
+            let msg = NetMessage::Leave {
+                name: String::from("Guest"),
+            };
+            match round_trip(*codec, msg) {
+                NetMessage::Leave { name } => assert_eq!(name, "Guest"),
+                msg => panic!("Unexpected message {:?}", msg),
+            }
         }
     }
+
+    #[test]
+    fn message_gameover_round_trip_test() {
+        for codec in CODECS {
+            assert!(matches!(
+                round_trip(*codec, NetMessage::GameOver),
+                NetMessage::GameOver
+            ));
+        }
+    }
+
+    #[test]
+    fn message_heartbeat_round_trip_test() {
+        for codec in CODECS {
+            assert!(matches!(
+                round_trip(*codec, NetMessage::Heartbeat),
+                NetMessage::Heartbeat
+            ));
+        }
+    }
+
+    #[test]
+    fn message_player_name_is_not_a_message_test() {
+        // A player name or any text looking like the old string prefixes must not be
+        // mistaken for a message.
+        let msg = Codec::Json.encode(&NetMessage::Hello {
+            name: String::from("GameData: {}"),
+            protocol: Protocol::current(),
+            spectator: false,
+        });
+        match Codec::decode(&msg).unwrap() {
+            NetMessage::Hello { name, .. } => assert_eq!(name, "GameData: {}"),
+            msg => panic!("Unexpected message {:?}", msg),
+        }
+        assert!(Codec::decode(&Message::Text(String::from("GameData: {}"))).is_err());
+        assert!(Codec::decode(&Message::Text(String::from("User GameData: joined"))).is_err());
+        assert!(Codec::decode(&Message::Binary(vec![0xc1])).is_err());
+        assert!(Codec::decode(&Message::Ping(vec![])).is_err());
+    }
+
+    #[test]
+    fn malformed_message_is_dropped_test() {
+        let mut peer = fixture("Uggla", "host");
+        peer.players.push(ship("Guest"));
+
+        assert!(matches!(
+            peer.receive(Message::Text(String::from(
+                "{\"type\": \"GameData\", \"data\": 42}"
+            ))),
+            Err(NetworkError::Decode(_))
+        ));
+        assert!(matches!(
+            peer.receive(Message::Binary(vec![0, 1, 2])),
+            Err(NetworkError::Decode(_))
+        ));
+        assert!(peer
+            .receive(Message::Text(String::from("User Guest joined")))
+            .is_ok());
+        assert!(peer
+            .receive(Message::Text(String::from(
+                "User Guest left on error: Connection reset"
+            )))
+            .is_ok());
+        assert!(peer
+            .receive(Codec::Json.encode(&NetMessage::Leave {
+                name: String::from("Guest")
+            }))
+            .is_ok());
+        assert_eq!(peer.players.len(), 1);
+        assert!(peer.session.stats.lines()[0].ends_with("2 malformed"));
+    }
+
+    #[test]
+    fn guest_inputs_test() {
+        let mut peer = fixture("Uggla", "host");
+
+        // A ship is created for a guest sending its inputs.
+        peer.receive_msg(NetMessage::Inputs {
+            name: String::from("Guest"),
+            inputs: vec![Input {
+                seq: 1,
+                ..Input::new(false, 1, false, 1.)
+            }],
+        });
+        assert_eq!(peer.players.len(), 2);
+        assert_eq!(
+            peer.session.inputs.names().collect::<Vec<_>>(),
+            vec!["Guest"]
+        );
+        peer.session.inputs.apply(&mut peer.players, &peer.world);
+        assert_eq!(peer.players[1].rot(), 5.);
+
+        // The ship sent by a guest sending its inputs is ignored.
+        let mut guest_ship = ship("Guest");
+        guest_ship.set_rot(45.);
+        let guestdata = || {
+            NetMessage::GuestData(GuestData {
+                asteroids: field(),
+                ship: guest_ship.clone(),
+            })
+        };
+        peer.receive_msg(guestdata());
+        assert_eq!(peer.session.inputs.names().count(), 1);
+        assert_eq!(peer.players[1].rot(), 5.);
+
+        // Fallback to the ship sent by a guest without inputs.
+        peer.receive_msg(NetMessage::Hello {
+            name: String::from("Guest"),
+            protocol: Protocol {
+                capabilities: Vec::new(),
+                ..Protocol::current()
+            },
+            spectator: false,
+        });
+        peer.receive_msg(guestdata());
+        assert_eq!(peer.session.inputs.names().count(), 0);
+        assert_eq!(peer.players[1].rot(), 45.);
+    }
+
+    #[test]
+    fn host_handshake_test() {
+        let mut peer = fixture("Uggla", "host");
+        let hello = |name: &str, protocol: Protocol, spectator: bool| NetMessage::Hello {
+            name: String::from(name),
+            protocol,
+            spectator,
+        };
+
+        peer.receive_msg(hello("Guest", Protocol::current(), false));
+        peer.receive_msg(hello("Spectator", Protocol::current(), true));
+        assert_eq!(peer.players.len(), 2);
+        assert_eq!(peer.players[1].name(), "Guest");
+
+        // An older build is rejected and its messages are ignored.
+        peer.receive_msg(hello("Old", Protocol::default(), false));
+        peer.receive_msg(NetMessage::Inputs {
+            name: String::from("Old"),
+            inputs: Vec::new(),
+        });
+        assert_eq!(peer.players.len(), 2);
+
+        let answers = peer.session.handshake.answers();
+        assert_eq!(answers.len(), 3);
+        assert!(matches!(&answers[2], NetMessage::Reject { name, .. } if name == "Old"));
+    }
+
+    #[test]
+    fn host_late_join_test() {
+        let mut peer = fixture("Uggla", "host");
+        let hello = || NetMessage::Hello {
+            name: String::from("Guest"),
+            protocol: Protocol::current(),
+            spectator: false,
+        };
+
+        // Joining on the gameover screen waits for the next round.
+        peer.gameover = true;
+        peer.receive_msg(hello());
+        assert!(matches!(
+            &peer.session.handshake.answers()[0],
+            NetMessage::Welcome { gameover: true, .. }
+        ));
+        assert_eq!(peer.players.len(), 1);
+
+        // Joining a running round spawns a protected ship.
+        peer.gameover = false;
+        peer.receive_msg(hello());
+        assert!(matches!(
+            &peer.session.handshake.answers()[0],
+            NetMessage::Welcome {
+                gameover: false,
+                ..
+            }
+        ));
+        assert_eq!(peer.players.len(), 2);
+        assert!(peer.players[1].is_protected(clock::now()));
+    }
+
+    #[test]
+    fn guest_hello_test() {
+        let mut peer = fixture("Guest", "guest");
+        peer.receive_msg(NetMessage::Hello {
+            name: String::from("Other"),
+            protocol: Protocol::current(),
+            spectator: false,
+        });
+
+        // Only the host spawns the ship, the simulation of the guest is untouched.
+        assert_eq!(peer.players.len(), 1);
+        assert!(peer
+            .asteroids
+            .get_asteroids()
+            .values()
+            .all(|asteroid| asteroid.last_updated() == 0.));
+        assert_eq!(
+            peer.world.rng.gen_range(0., 1.),
+            Rng::new(42).gen_range(0., 1.)
+        );
+    }
+
+    #[test]
+    fn migrate_ship_state_guest_test() {
+        // Guest and Legacy play with Uggla, Legacy sends its whole ship.
+        let mut guest = fixture("Guest", "guest");
+        guest.players.extend([ship("Guest"), ship("Legacy")]);
+        let mut legacy = fixture("Legacy", "guest");
+        legacy.players = guest.players.clone();
+        legacy.session.handshake.send_ship_state();
+        legacy
+            .session
+            .handshake
+            .welcome_received(Protocol::current(), false, Some(42));
+
+        // Both elect Guest when Uggla leaves.
+        for peer in [&mut guest, &mut legacy] {
+            let players = peer.players.clone();
+            peer.session
+                .roster
+                .update_members("Uggla", &players, clock::now());
+            peer.receive_msg(NetMessage::Leave {
+                name: String::from("Uggla"),
+            });
+            let lost_host = peer.session.roster.host_lost(clock::now()).unwrap();
+            migrate_host(
+                &lost_host,
+                &mut peer.session,
+                &mut peer.asteroids,
+                &mut peer.players,
+                &mut peer.world,
+            );
+        }
+        assert_eq!(guest.session.mode, "host");
+        assert_eq!(legacy.session.mode, "guest");
+
+        // Legacy says hello again, the new host keeps applying its ship.
+        let hello = legacy
+            .session
+            .handshake
+            .hello("Legacy", false, clock::now())
+            .unwrap();
+        guest.receive_msg(hello);
+        assert!(matches!(
+            &guest.session.handshake.answers()[0],
+            NetMessage::Welcome { name, .. } if name == "Legacy"
+        ));
+        let mut legacy_ship = ship("Legacy");
+        legacy_ship.set_rot(45.);
+        guest.receive_msg(NetMessage::GuestData(GuestData {
+            asteroids: field(),
+            ship: legacy_ship,
+        }));
+        assert_eq!(guest.players.len(), 2);
+        assert_eq!(guest.players[1].rot(), 45.);
+    }
+
+    #[test]
+    fn late_join_split_test() {
+        // The host generated its field and split an asteroid before the guest joins.
+        let mut host_world = World::new(screen::WIDTH, screen::HEIGHT, 42);
+        let mut host_asteroids = Asteroids::generate_field(String::from("f1"), 5, &mut host_world);
+        let split = |rng: &mut Rng| {
+            serde_json::to_string(&Asteroid::new_split(
+                Vec2::new(512., 384.),
+                1.,
+                0.,
+                50.,
+                8,
+                1.,
+                rng,
+            ))
+            .unwrap()
+        };
+        split(&mut host_world.rng);
+        let keyframe = serialize_host_data(
+            Codec::Json,
+            "Uggla",
+            &mut Snapshots::new(),
+            &mut host_asteroids,
+            &mut [ship("Uggla")],
+            &InputQueues::new(),
+            &host_world,
+        );
+
+        // The guest took the seed of the host with the welcome.
+        let mut world = World::new(screen::WIDTH, screen::HEIGHT, 42);
+        deserialize_host_data(
+            &mut Session::new("Guest", "guest", Codec::Json, 5., 42),
+            keyframe,
+            &mut Asteroids::default(),
+            &mut Vec::new(),
+            &mut false,
+            &mut world,
+        )
+        .unwrap();
+
+        // The seed alone does not reproduce the next split, the keyframe does.
+        let next_split = split(&mut host_world.rng);
+        assert_ne!(split(&mut Rng::new(42)), next_split);
+        assert_eq!(split(&mut world.rng), next_split);
+    }
+
+    #[test]
+    fn game_url_test() {
+        let url = game_url("localhost", 8080, None, "Uggla").unwrap();
+        assert_eq!(url.as_str(), "ws://localhost:8080/gamedata/Uggla");
+        let url = game_url("localhost", 8080, Some("game 1"), "Uggla/2").unwrap();
+        assert_eq!(
+            url.as_str(),
+            "ws://localhost:8080/gamedata/game%201/Uggla%2F2"
+        );
+        assert!(matches!(
+            game_url("local host", 8080, None, "Uggla"),
+            Err(NetworkError::Url(_))
+        ));
+    }
+
+    #[test]
+    fn connection_refused_is_reported_test() {
+        // Get a free port and close it.
+        let port = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let url = game_url("127.0.0.1", port, None, "Uggla").unwrap();
+        assert!(matches!(connect_stream(&url), Err(NetworkError::Io(_))));
+
+        let (tx_from_socket, rx_from_socket) = mpsc::channel();
+        let (tx_to_socket, rx_to_socket) = mpsc::channel();
+        let network_thread = thread::spawn(move || {
+            run_socket(url, NetConditions::default(), rx_to_socket, tx_from_socket)
+        });
+        assert!(matches!(
+            rx_from_socket.recv(),
+            Ok(NetworkEvent::Disconnected(NetworkError::Io(_)))
+        ));
+        drop(tx_to_socket);
+        network_thread.join().unwrap();
+    }
+
+    #[test]
+    fn reconnect_test() {
+        // A minimal worker accepting a connection, closing it and accepting a new one.
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let worker = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut socket = tungstenite::accept(stream).unwrap();
+            socket.close(None).unwrap();
+            while socket.read_message().is_ok() {}
+            drop(socket);
+
+            let (stream, _) = listener.accept().unwrap();
+            let mut socket = tungstenite::accept(stream).unwrap();
+            socket
+                .write_message(Codec::Json.encode(&NetMessage::GameOver))
+                .unwrap();
+            socket.read_message().unwrap()
+        });
+
+        let url = game_url("127.0.0.1", port, None, "Uggla").unwrap();
+        let (tx_from_socket, rx_from_socket) = mpsc::channel();
+        let (tx_to_socket, rx_to_socket) = mpsc::channel();
+        let network_thread = thread::spawn(move || {
+            run_socket(url, NetConditions::default(), rx_to_socket, tx_from_socket)
+        });
+
+        assert!(matches!(rx_from_socket.recv(), Ok(NetworkEvent::Connected)));
+        assert!(matches!(
+            rx_from_socket.recv(),
+            Ok(NetworkEvent::Disconnected(NetworkError::Disconnected))
+        ));
+        assert!(matches!(rx_from_socket.recv(), Ok(NetworkEvent::Connected)));
+        match rx_from_socket.recv() {
+            Ok(NetworkEvent::Message(msg)) => {
+                assert!(matches!(Codec::decode(&msg), Ok(NetMessage::GameOver)))
+            }
+            event => panic!("Unexpected event {:?}", event),
+        }
+        tx_to_socket
+            .send(Codec::Json.encode(&NetMessage::Leave {
+                name: String::from("Uggla"),
+            }))
+            .unwrap();
+        assert!(matches!(
+            Codec::decode(&worker.join().unwrap()),
+            Ok(NetMessage::Leave { .. })
+        ));
+        drop(tx_to_socket);
+        network_thread.join().unwrap();
+    }
+
+    #[test]
+    fn simulated_latency_test() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let worker = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut socket = tungstenite::accept(stream).unwrap();
+            socket
+                .write_message(Codec::Json.encode(&NetMessage::GameOver))
+                .unwrap();
+            socket.read_message().unwrap()
+        });
+
+        let url = game_url("127.0.0.1", port, None, "Uggla").unwrap();
+        let mut connection = Connection::open_simulated(
+            url,
+            NetConditions {
+                latency: Duration::from_millis(200),
+                ..NetConditions::default()
+            },
+        );
+        let start = Instant::now();
+        let msg = loop {
+            match connection.try_recv() {
+                Ok(NetworkEvent::Message(msg)) => break msg,
+                Ok(_) | Err(TryRecvError::Empty) => sleep(Duration::from_millis(5)),
+                Err(e) => panic!("Unexpected error {:?}", e),
+            }
+        };
+        assert!(start.elapsed() >= Duration::from_millis(200));
+        assert!(matches!(Codec::decode(&msg), Ok(NetMessage::GameOver)));
+
+        // Pending messages are written when the game exits.
+        connection.send(Codec::Json.encode(&NetMessage::Leave {
+            name: String::from("Uggla"),
+        }));
+        connection.close();
+        assert!(matches!(
+            Codec::decode(&worker.join().unwrap()),
+            Ok(NetMessage::Leave { .. })
+        ));
+    }
+
+    #[test]
+    fn relay_test() {
+        let relay = planetoid_relay::Relay::bind("127.0.0.1:0").unwrap();
+        let port = relay.local_addr().unwrap().port();
+        let relay = relay.spawn();
+
+        let mut peers = Vec::new();
+        for name in ["Uggla", "Guest"] {
+            let url = game_url("127.0.0.1", port, Some("game1"), name).unwrap();
+            let (tx_from_socket, rx_from_socket) = mpsc::channel();
+            let (tx_to_socket, rx_to_socket) = mpsc::channel();
+            let network_thread = thread::spawn(move || {
+                run_socket(url, NetConditions::default(), rx_to_socket, tx_from_socket)
+            });
+            assert!(matches!(rx_from_socket.recv(), Ok(NetworkEvent::Connected)));
+            // The join notice is received once the session is registered.
+            match rx_from_socket.recv() {
+                Ok(NetworkEvent::Message(msg)) => {
+                    assert_eq!(msg.to_text().unwrap(), format!("User {} joined", name))
+                }
+                event => panic!("Unexpected event {:?}", event),
+            }
+            peers.push((tx_to_socket, rx_from_socket, network_thread));
+        }
+
+        // Join notices of other players are not game messages.
+        let next_message = |rx: &Receiver<NetworkEvent>| loop {
+            match rx.recv() {
+                Ok(NetworkEvent::Message(msg)) => match Codec::decode(&msg) {
+                    Ok(message) => return message,
+                    Err(_) => assert!(msg.to_text().unwrap().ends_with("joined")),
+                },
+                event => panic!("Unexpected event {:?}", event),
+            }
+        };
+        let hello = NetMessage::Hello {
+            name: String::from("Guest"),
+            protocol: Protocol::current(),
+            spectator: false,
+        };
+        peers[1].0.send(Codec::MessagePack.encode(&hello)).unwrap();
+        assert!(
+            matches!(next_message(&peers[0].1), NetMessage::Hello { name, .. } if name == "Guest")
+        );
+        peers[0]
+            .0
+            .send(Codec::Json.encode(&NetMessage::GameOver))
+            .unwrap();
+        // Messages are also relayed to their sender.
+        assert!(matches!(
+            next_message(&peers[1].1),
+            NetMessage::Hello { .. }
+        ));
+        assert!(matches!(next_message(&peers[1].1), NetMessage::GameOver));
+
+        for (tx_to_socket, _rx_from_socket, network_thread) in peers {
+            drop(tx_to_socket);
+            network_thread.join().unwrap();
+        }
+        relay.stop().unwrap();
+    }
+
+    #[test]
+    fn codec_from_str_test() {
+        for value in Codec::VALUES {
+            assert!(Codec::from_str(value).is_ok());
+        }
+        assert_eq!(Codec::from_str("msgpack"), Ok(Codec::MessagePack));
+        assert!(Codec::from_str("xml").is_err());
+    }
+
+    #[test]
+    fn codec_size_test() {
+        // A field of the size generated by the host, with a few bullets in flight.
+        let mut world = World::new(screen::WIDTH, screen::HEIGHT, 42);
+        let mut asteroids = Asteroids::generate_field(String::from("f1"), 20, &mut world);
+        let mut players = vec![ship("Uggla"), ship("Guest")];
+        for _ in 0..10 {
+            players[0].shoot(1.);
+        }
+
+        let json = serialize_host_data(
+            Codec::Json,
+            "Uggla",
+            &mut Snapshots::new(),
+            &mut asteroids,
+            &mut players,
+            &InputQueues::new(),
+            &world,
+        );
+        let msgpack = serialize_host_data(
+            Codec::MessagePack,
+            "Uggla",
+            &mut Snapshots::new(),
+            &mut asteroids,
+            &mut players,
+            &InputQueues::new(),
+            &world,
+        );
+        dbg!(json.len(), msgpack.len());
+        assert!(msgpack.is_binary());
+        assert!(msgpack.len() < json.len() * 4 / 5);
+    }
+
+    #[test]
+    fn snapshot_delta_size_test() {
+        let mut asteroids = Asteroids::default();
+        for i in 0..20 {
+            asteroids.add_asteroid(
+                String::from("f1"),
+                Asteroid::new_pos_and_size(i as f32 * 10.1, i as f32 * 7.3, 76.8),
+            );
+        }
+        let mut players = vec![ship("Uggla"), ship("Guest")];
+        let mut snapshots = Snapshots::new();
+        let world = World::new(screen::WIDTH, screen::HEIGHT, 42);
+
+        let keyframe = serialize_host_data(
+            Codec::Json,
+            "Uggla",
+            &mut snapshots,
+            &mut asteroids,
+            &mut players,
+            &InputQueues::new(),
+            &world,
+        );
+        snapshots.ack("Guest", 1);
+        players[0].set_rot(90.);
+        let delta = serialize_host_data(
+            Codec::Json,
+            "Uggla",
+            &mut snapshots,
+            &mut asteroids,
+            &mut players,
+            &InputQueues::new(),
+            &world,
+        );
+        dbg!(keyframe.len(), delta.len());
+        assert!(delta.len() < keyframe.len() / 5);
+    }
+
+    #[test]
+    fn codec_stream_test() {
+        // A stream of snapshots, keyframes and deltas, is decoded by both
+        // codecs and is smaller with MessagePack.
+        let world = World::new(screen::WIDTH, screen::HEIGHT, 42);
+        let stream = |codec: Codec| {
+            let mut asteroids = field();
+            let mut players = vec![ship("Uggla"), ship("Guest")];
+            let mut snapshots = Snapshots::new();
+            let mut bytes = 0;
+            for i in 0..100 {
+                players[1].set_rot(i as f32);
+                let msg = serialize_host_data(
+                    codec,
+                    "Uggla",
+                    &mut snapshots,
+                    &mut asteroids,
+                    &mut players,
+                    &InputQueues::new(),
+                    &world,
+                );
+                bytes += msg.len();
+                match Codec::decode(&msg).unwrap() {
+                    NetMessage::GameData(gamedata) => snapshots.ack("Guest", gamedata.id()),
+                    msg => panic!("Unexpected message {:?}", msg),
+                };
+            }
+            bytes
+        };
+        assert!(stream(Codec::MessagePack) < stream(Codec::Json));
+    }
+
+    #[test]
+    #[ignore]
+    fn codec_throughput_test() {
+        // MessagePack is cheaper to decode and encode than JSON. Timings
+        // depend on the machine, run it with
+        // `cargo test --release codec_throughput_test -- --ignored`.
+        let mut world = World::new(screen::WIDTH, screen::HEIGHT, 42);
+        let mut asteroids = Asteroids::generate_field(String::from("f1"), 20, &mut world);
+        let mut players = vec![ship("Uggla"), ship("Guest")];
+        let mut msg = |codec: Codec| {
+            serialize_host_data(
+                codec,
+                "Uggla",
+                &mut Snapshots::new(),
+                &mut asteroids,
+                &mut players,
+                &InputQueues::new(),
+                &world,
+            )
+        };
+        // Decoding and encoding again the same keyframe 1000 times, best of 5 runs.
+        let mut elapsed = |codec: Codec| {
+            let msg = msg(codec);
+            (0..5)
+                .map(|_| {
+                    let start = Instant::now();
+                    for _ in 0..1000 {
+                        codec.encode(&Codec::decode(&msg).unwrap());
+                    }
+                    start.elapsed()
+                })
+                .min()
+                .unwrap()
+        };
+        let json = elapsed(Codec::Json);
+        let msgpack = elapsed(Codec::MessagePack);
+        assert!(msgpack < json, "MessagePack {:?}, JSON {:?}", msgpack, json);
+    }
 }
@@ -0,0 +1,55 @@
+//! Drawing of the entities of the simulation, which knows nothing about the
+//! window.
+
+use macroquad::prelude::*;
+use planetoid_core::asteroid::Asteroid;
+use planetoid_core::bullet::Bullet;
+use planetoid_core::collision::Collided;
+use planetoid_core::ship::Ship;
+
+pub fn draw_ship(ship: &Ship, color: Color) {
+    let pos = ship.pos();
+    let rotation = ship.rotation();
+    let v1 = Vec2::new(
+        pos.x + rotation.sin() * Ship::HEIGHT / 2.,
+        pos.y - rotation.cos() * Ship::HEIGHT / 2.,
+    );
+    let v2 = Vec2::new(
+        pos.x - rotation.cos() * Ship::BASE / 2. - rotation.sin() * Ship::HEIGHT / 2.,
+        pos.y - rotation.sin() * Ship::BASE / 2. + rotation.cos() * Ship::HEIGHT / 2.,
+    );
+    let v3 = Vec2::new(
+        pos.x + rotation.cos() * Ship::BASE / 2. - rotation.sin() * Ship::HEIGHT / 2.,
+        pos.y + rotation.sin() * Ship::BASE / 2. + rotation.cos() * Ship::HEIGHT / 2.,
+    );
+    let v1_2 = Vec2::new(
+        pos.x + rotation.sin() * Ship::HEIGHT / 4.,
+        pos.y - rotation.cos() * Ship::HEIGHT / 4.,
+    );
+    let v2_2 = Vec2::new(
+        pos.x - rotation.cos() * Ship::BASE / 4. - rotation.sin() * Ship::HEIGHT / 4.,
+        pos.y - rotation.sin() * Ship::BASE / 4. + rotation.cos() * Ship::HEIGHT / 4.,
+    );
+    let v3_2 = Vec2::new(
+        pos.x + rotation.cos() * Ship::BASE / 4. - rotation.sin() * Ship::HEIGHT / 4.,
+        pos.y + rotation.sin() * Ship::BASE / 4. + rotation.cos() * Ship::HEIGHT / 4.,
+    );
+    draw_triangle_lines(v1, v2, v3, 2., color);
+    draw_triangle_lines(v1_2, v2_2, v3_2, 2., color);
+}
+
+pub fn draw_bullet(bullet: &Bullet) {
+    draw_circle(bullet.pos().x, bullet.pos().y, 2., BLACK);
+}
+
+pub fn draw_asteroid(asteroid: &Asteroid) {
+    draw_poly_lines(
+        asteroid.pos().x,
+        asteroid.pos().y,
+        asteroid.sides(),
+        asteroid.size(),
+        asteroid.rot(),
+        2.,
+        BLACK,
+    )
+}
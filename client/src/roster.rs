@@ -0,0 +1,185 @@
+use planetoid_core::ship::Ship;
+use std::collections::HashMap;
+
+/// Players of the game.
+///
+/// The host tracks the last time it received a message from each peer, to
+/// drop the peers that left without saying goodbye (crash, closed window,
+/// lost connection).
+///
+/// Guests and spectators track the players in the order of the host, which
+/// is the join order with the host first, to elect the same new host when
+/// the host is lost.
+pub struct Roster {
+    timeout: f64,
+    last_seen: HashMap<String, f64>,
+    members: Vec<String>,
+    host_seen: f64,
+    left_host: Option<String>,
+}
+
+impl Roster {
+    pub fn new(timeout: f64) -> Self {
+        Self {
+            timeout,
+            last_seen: HashMap::new(),
+            members: Vec::new(),
+            host_seen: 0.,
+            left_host: None,
+        }
+    }
+
+    /// Forget all the peers, their timeout starts again.
+    pub fn clear(&mut self) {
+        self.last_seen.clear();
+        self.members.clear();
+        self.left_host = None;
+    }
+
+    pub fn seen(&mut self, name: &str, t: f64) {
+        self.last_seen.insert(name.to_string(), t);
+    }
+
+    pub fn remove(&mut self, name: &str) {
+        self.last_seen.remove(name);
+        if self.host() == Some(name) {
+            self.left_host = Some(self.members.remove(0));
+        } else {
+            self.members.retain(|member| member != name);
+        }
+    }
+
+    /// Return the peers not seen for `timeout` seconds and forget them.
+    /// The timeout of a ship not seen yet starts now, so players that never
+    /// send a message are also dropped.
+    pub fn timed_out(&mut self, players: &[Ship], local_name: &str, t: f64) -> Vec<String> {
+        for ship in players.iter().filter(|ship| ship.name() != local_name) {
+            self.last_seen.entry(ship.name()).or_insert(t);
+        }
+
+        let timeout = self.timeout;
+        let timed_out: Vec<String> = self
+            .last_seen
+            .iter()
+            .filter(|(_, last_seen)| t - **last_seen > timeout)
+            .map(|(name, _)| name.clone())
+            .collect();
+        for name in &timed_out {
+            self.last_seen.remove(name);
+        }
+        timed_out
+    }
+
+    /// Record the players of a snapshot received from `host`, in the order
+    /// of the host. The host is the first member even without a ship.
+    pub fn update_members(&mut self, host: &str, players: &[Ship], t: f64) {
+        self.members = vec![host.to_string()];
+        self.members.extend(
+            players
+                .iter()
+                .map(|ship| ship.name())
+                .filter(|name| name != host),
+        );
+        self.host_seen = t;
+    }
+
+    /// A message from the host that is not a snapshot.
+    pub fn host_seen(&mut self, t: f64) {
+        self.host_seen = t;
+    }
+
+    pub fn host(&self) -> Option<&str> {
+        self.members.first().map(|host| host.as_str())
+    }
+
+    /// Return the host if it left or was not seen for `timeout` seconds.
+    /// It is forgotten and the oldest remaining player becomes the host.
+    pub fn host_lost(&mut self, t: f64) -> Option<String> {
+        let lost = match self.left_host.take() {
+            Some(host) => host,
+            None if !self.members.is_empty() && t - self.host_seen > self.timeout => {
+                self.members.remove(0)
+            }
+            None => return None,
+        };
+        // Give some time to the new host to take over.
+        self.host_seen = t;
+        Some(lost)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use macroquad::prelude::Vec2;
+
+    fn ship(name: &str) -> Ship {
+        Ship::new_pos(String::from(name), Vec2::new(10., 20.))
+    }
+
+    #[test]
+    fn roster_timeout_test() {
+        let mut roster = Roster::new(5.);
+        let players = vec![ship("Uggla"), ship("Guest"), ship("Ghost")];
+
+        roster.seen("Spectator", 0.);
+        assert!(roster.timed_out(&players, "Uggla", 1.).is_empty());
+        roster.seen("Guest", 4.);
+        roster.seen("Spectator", 4.);
+        // The local player never times out, the ghost was first checked at 1.
+        assert!(roster.timed_out(&players, "Uggla", 6.).is_empty());
+        assert_eq!(roster.timed_out(&players, "Uggla", 6.5), vec!["Ghost"]);
+
+        let mut timed_out = roster.timed_out(&players[..2], "Uggla", 9.5);
+        timed_out.sort();
+        assert_eq!(timed_out, vec!["Guest", "Spectator"]);
+    }
+
+    #[test]
+    fn roster_leave_test() {
+        let mut roster = Roster::new(5.);
+        roster.seen("Spectator", 0.);
+        roster.remove("Spectator");
+        assert!(roster.timed_out(&[], "Uggla", 10.).is_empty());
+
+        roster.seen("Spectator", 0.);
+        roster.clear();
+        assert!(roster.timed_out(&[], "Uggla", 10.).is_empty());
+    }
+
+    #[test]
+    fn roster_host_lost_test() {
+        let mut roster = Roster::new(5.);
+        let players = vec![ship("Uggla"), ship("Guest 1"), ship("Guest 2")];
+        roster.update_members("Uggla", &players, 0.);
+        assert_eq!(roster.host(), Some("Uggla"));
+
+        roster.host_seen(3.);
+        assert_eq!(roster.host_lost(8.), None);
+        assert_eq!(roster.host_lost(8.5), Some(String::from("Uggla")));
+        assert_eq!(roster.host(), Some("Guest 1"));
+        assert_eq!(roster.host_lost(13.), None);
+
+        // A guest leaving is not a host change.
+        roster.remove("Guest 2");
+        assert_eq!(roster.host_lost(13.), None);
+        roster.remove("Guest 1");
+        assert_eq!(roster.host_lost(13.), Some(String::from("Guest 1")));
+        assert_eq!(roster.host(), None);
+        assert_eq!(roster.host_lost(100.), None);
+    }
+
+    #[test]
+    fn roster_server_host_test() {
+        let mut roster = Roster::new(5.);
+        let players = vec![ship("Guest 1"), ship("Guest 2")];
+        roster.update_members("Server", &players, 0.);
+        assert_eq!(roster.host(), Some("Server"));
+
+        // The first guest leaving is not a host change.
+        roster.remove("Guest 1");
+        assert_eq!(roster.host_lost(1.), None);
+        assert_eq!(roster.host_lost(5.5), Some(String::from("Server")));
+        assert_eq!(roster.host(), Some("Guest 2"));
+    }
+}
@@ -0,0 +1,184 @@
+use crate::clock::{self, Ticker};
+use crate::netsim::NetConditions;
+use crate::network::{
+    deserialize_host_data, serialize_host_data, Codec, Connection, NetMessage, NetworkEvent,
+};
+use crate::screen;
+use crate::session::Session;
+use crate::MAX_ASTEROIDS;
+use planetoid_core::asteroid::Asteroids;
+use planetoid_core::ship::Ship;
+use planetoid_core::world::{self, World};
+use std::sync::mpsc::TryRecvError;
+use std::thread;
+use std::time::{Duration, Instant};
+use url::Url;
+
+/// Pace of the loop, the ticks elapsed meanwhile are simulated.
+const FRAME_DURATION: Duration = Duration::from_micros(16_667);
+/// Seconds on the gameover screen before the next game starts.
+const RESTART_DELAY: f64 = 5.;
+
+/// Host the game without window, sound nor ship of its own.
+///
+/// The server runs the simulation of the host: it generates the asteroids,
+/// moves every entity, resolves the collisions and sends the snapshots to the
+/// guests and spectators. A new game starts on its own after a gameover, the
+/// fields of the games follow from `seed`.
+pub fn run(url: Url, conditions: NetConditions, codec: Codec, name: &str, timeout: f64, seed: u64) {
+    log::info!("Starting server.");
+    let mut connection = Connection::open_simulated(url, conditions);
+    // The server never leaves the host mode, guests cannot take over while
+    // it is connected.
+    let mut session = Session::new(name, "host", codec, timeout, seed);
+    let mut online = false;
+
+    let mut world = World::new(screen::WIDTH, screen::HEIGHT, seed);
+    let mut players: Vec<Ship> = Vec::new();
+    let mut asteroids = Asteroids::generate_field(name.to_string(), MAX_ASTEROIDS, &mut world);
+    let mut gameover = false;
+    let mut gameover_t = 0.;
+    session.snapshots.new_round(session.clock_sync.now());
+    // Ticks since the last snapshot sent.
+    let mut tick_count: u32 = 0;
+    let mut ticker = Ticker::new(clock::now());
+    let mut heartbeat_t = clock::now();
+
+    loop {
+        let frame_start = Instant::now();
+
+        loop {
+            match connection.try_recv() {
+                Ok(NetworkEvent::Connected) => {
+                    log::info!("Connected.");
+                    online = true;
+                    session.snapshots.reset();
+                    session.roster.clear();
+                }
+                Ok(NetworkEvent::Message(msg)) => {
+                    if let Err(e) = deserialize_host_data(
+                        &mut session,
+                        msg,
+                        &mut asteroids,
+                        &mut players,
+                        &mut gameover,
+                        &mut world,
+                    ) {
+                        log::warn!("Dropping message: {}", e);
+                    }
+                }
+                Ok(NetworkEvent::Disconnected(e)) => {
+                    log::error!("{}, reconnecting...", e);
+                    online = false;
+                }
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => {
+                    log::error!("Network thread stopped.");
+                    return;
+                }
+            }
+        }
+
+        // Logged at debug level every second.
+        session.stats.update(clock::now(), connection.sent());
+
+        if online {
+            for msg in session
+                .handshake
+                .answers()
+                .iter()
+                .chain(&session.clock_sync.pongs())
+            {
+                connection.send(codec.encode(msg));
+            }
+        }
+
+        if gameover {
+            // Let the peers know the host is still there until a new game starts.
+            if online && clock::now() - heartbeat_t > 1. {
+                connection.send(codec.encode(&NetMessage::Heartbeat));
+                heartbeat_t = clock::now();
+            }
+
+            if clock::now() - gameover_t > RESTART_DELAY {
+                log::info!("Restarting game.");
+                // Guests get a new ship with their first session.inputs.
+                players.clear();
+                asteroids = Asteroids::generate_field(name.to_string(), MAX_ASTEROIDS, &mut world);
+                gameover = false;
+                tick_count = 0;
+                // New game, peers need a keyframe
+                session.snapshots.new_round(session.clock_sync.now());
+                session.snapshots.reset();
+                session.roster.clear();
+                session.inputs.clear();
+                ticker.reset(clock::now());
+            }
+        } else {
+            // Drop the players that left without a Leave message, the next
+            // snapshot lets the other players know.
+            if online {
+                for peer in session.roster.timed_out(&players, name, clock::now()) {
+                    log::info!("{} timed out", peer);
+                    players.retain(|ship| ship.name() != peer);
+                    session.snapshots.remove_peer(&peer);
+                    session.inputs.remove(&peer);
+                    session.stats.remove(&peer);
+                }
+            }
+
+            let peer = world::Peer {
+                name,
+                mode: &session.mode,
+                god: false,
+                simulates_own_ship: false,
+            };
+            // The simulation runs by fixed ticks, like the game loop.
+            for _ in 0..ticker.ticks(clock::now()) {
+                let round_over = world::step(
+                    &mut world,
+                    &mut players,
+                    &mut asteroids,
+                    &mut session.inputs,
+                    None,
+                    &peer,
+                    session.clock_sync.now(),
+                );
+
+                tick_count += 1;
+                if round_over {
+                    log::info!("Game over.");
+                    gameover = true;
+                    gameover_t = clock::now();
+                    // Send a last message to all guests that the game is over
+                    if online {
+                        connection.send(serialize_host_data(
+                            codec,
+                            name,
+                            &mut session.snapshots,
+                            &mut asteroids,
+                            &mut players,
+                            &session.inputs,
+                            &world,
+                        ));
+                        connection.send(codec.encode(&NetMessage::GameOver));
+                    }
+                    break;
+                } else if online && tick_count > 4 {
+                    connection.send(serialize_host_data(
+                        codec,
+                        name,
+                        &mut session.snapshots,
+                        &mut asteroids,
+                        &mut players,
+                        &session.inputs,
+                        &world,
+                    ));
+                    tick_count = 0;
+                }
+            }
+        }
+
+        thread::sleep(FRAME_DURATION.saturating_sub(frame_start.elapsed()));
+    }
+}
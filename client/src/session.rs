@@ -0,0 +1,49 @@
+use crate::chat::Chat;
+use crate::handshake::Handshake;
+use crate::network::{ClockSync, Codec};
+use crate::roster::Roster;
+use crate::snapshot::Snapshots;
+use crate::stats::NetStats;
+use planetoid_core::input::{InputLog, InputQueues};
+
+/// Network state of this peer, as the host of the game or as a guest or a
+/// spectator.
+pub struct Session {
+    pub name: String,
+    /// A guest becomes the host if the host is lost.
+    pub mode: String,
+    pub codec: Codec,
+    /// The host sent a message since the last acknowledgement of this peer.
+    pub host_msg_received: bool,
+    pub clock_sync: ClockSync,
+    pub snapshots: Snapshots,
+    pub roster: Roster,
+    pub handshake: Handshake,
+    /// Inputs of the guests simulated by the host.
+    pub inputs: InputQueues,
+    /// Inputs of the local player not applied by the host yet.
+    pub input_log: InputLog,
+    pub stats: NetStats,
+    pub chat: Chat,
+}
+
+impl Session {
+    /// Peers are dropped after `timeout` seconds without message, `seed` is
+    /// the seed of the simulation until the host gives its own.
+    pub fn new(name: &str, mode: &str, codec: Codec, timeout: f64, seed: u64) -> Self {
+        Self {
+            name: name.to_string(),
+            mode: mode.to_string(),
+            codec,
+            host_msg_received: false,
+            clock_sync: ClockSync::new(),
+            snapshots: Snapshots::new(),
+            roster: Roster::new(timeout),
+            handshake: Handshake::new(seed),
+            inputs: InputQueues::new(),
+            input_log: InputLog::new(),
+            stats: NetStats::new(),
+            chat: Chat::new(),
+        }
+    }
+}
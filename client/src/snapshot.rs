@@ -0,0 +1,678 @@
+use crate::clock;
+use planetoid_core::asteroid::{diff_asteroids, synchronize_asteroids, Asteroids};
+use planetoid_core::rng::Rng;
+use planetoid_core::ship::Ship;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, VecDeque};
+
+/// Round of the game, from its start to the gameover.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+pub struct Round {
+    /// Rounds started by the hosts of the game, 0 for the older builds.
+    pub number: u32,
+    /// Time of the host when the round started.
+    pub started_t: f64,
+}
+
+/// Game state sent by the host.
+/// A keyframe (`base` is `None`) contains the whole state, otherwise only the
+/// entities changed since the snapshot `base`.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct GameData {
+    id: u32,
+    host: String,
+    base: Option<u32>,
+    asteroids: Asteroids,
+    removed_asteroids: Vec<String>,
+    players: Vec<Ship>,
+    removed_players: Vec<String>,
+    /// Last input applied by the host for each ship it simulates from the
+    /// inputs of its guest.
+    inputs: BTreeMap<String, u32>,
+    /// Round the snapshot belongs to, so players joining know what they join.
+    #[serde(default)]
+    round: Round,
+    /// Random generator of the host, sent with the keyframes so players
+    /// joining split the asteroids like the host.
+    #[serde(default)]
+    rng: Option<Rng>,
+}
+
+impl GameData {
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+
+    pub fn is_keyframe(&self) -> bool {
+        self.base.is_none()
+    }
+
+    /// Name of the host, which may have no ship in server mode.
+    pub fn host(&self) -> &str {
+        &self.host
+    }
+
+    pub fn set_inputs(&mut self, inputs: BTreeMap<String, u32>) {
+        self.inputs = inputs;
+    }
+
+    pub fn set_rng(&mut self, rng: Rng) {
+        self.rng = Some(rng);
+    }
+
+    pub fn take_rng(&mut self) -> Option<Rng> {
+        self.rng.take()
+    }
+}
+
+struct Snapshot {
+    id: u32,
+    /// Local time when the snapshot was sent, to measure the round trip
+    /// time when it is acknowledged.
+    sent_t: f64,
+    asteroids: Asteroids,
+    players: Vec<Ship>,
+}
+
+/// Track the snapshots sent by the host and acknowledged by the peers,
+/// or the last snapshot applied by a guest or a spectator.
+pub struct Snapshots {
+    next_id: u32,
+    history: VecDeque<Snapshot>,
+    acks: HashMap<String, u32>,
+    keyframe_requested: bool,
+    since_keyframe: u32,
+    applied: u32,
+    /// Last state of the local ship sent by the host simulating it.
+    confirmed: Option<Ship>,
+    /// Last input of the local ship applied by the host, if not reconciled yet.
+    acked_input: Option<u32>,
+    /// Round played by the host, kept by a guest taking over.
+    round: Round,
+}
+
+impl Snapshots {
+    /// Send a keyframe every 30 snapshots (every 150 ticks) to resync peers.
+    const KEYFRAME_INTERVAL: u32 = 30;
+    const HISTORY_SIZE: usize = 64;
+
+    pub fn new() -> Self {
+        Self {
+            next_id: 1,
+            history: VecDeque::new(),
+            acks: HashMap::new(),
+            keyframe_requested: true,
+            since_keyframe: 0,
+            applied: 0,
+            confirmed: None,
+            acked_input: None,
+            round: Round::default(),
+        }
+    }
+
+    /// Forget the peers and the history, the next snapshot will be a keyframe.
+    pub fn reset(&mut self) {
+        self.history.clear();
+        self.acks.clear();
+        self.keyframe_requested = true;
+        self.applied = 0;
+        self.confirmed = None;
+        self.acked_input = None;
+    }
+
+    /// A guest becomes the host, its snapshot ids follow the ones of the
+    /// previous host so peers do not mistake them for already applied ones.
+    pub fn promote(&mut self) {
+        self.next_id = self.next_id.max(self.applied + 1);
+        self.reset();
+    }
+
+    /// Host side, start a new round at `t`.
+    pub fn new_round(&mut self, t: f64) {
+        self.round = Round {
+            number: self.round.number + 1,
+            started_t: t,
+        };
+        log::info!("Round {} started", self.round.number);
+    }
+
+    pub fn round(&self) -> Round {
+        self.round
+    }
+
+    /// Last snapshot applied by a guest or a spectator, 0 if none.
+    pub fn applied(&self) -> u32 {
+        self.applied
+    }
+
+    /// State of the local ship confirmed by the host and the last input it
+    /// applied, once per snapshot bringing the ship, to replay the inputs the
+    /// host did not apply yet.
+    pub fn take_confirmed(&mut self) -> Option<(u32, Ship)> {
+        self.acked_input.take().zip(self.confirmed.clone())
+    }
+
+    /// Record a snapshot acknowledged by a peer. Return the round trip time
+    /// of the snapshot the first time the peer acknowledges it.
+    pub fn ack(&mut self, name: &str, id: u32) -> Option<f64> {
+        let sent_t = match self.history.iter().find(|snapshot| snapshot.id == id) {
+            Some(snapshot) => Some(snapshot.sent_t),
+            None => {
+                // The peer just joined or is too late, it needs the whole state.
+                self.keyframe_requested = true;
+                None
+            }
+        };
+        let previous = self.acks.insert(name.to_string(), id);
+        sent_t
+            .filter(|_| previous != Some(id))
+            .map(|sent_t| clock::now() - sent_t)
+    }
+
+    pub fn remove_peer(&mut self, name: &str) {
+        self.acks.remove(name);
+    }
+
+    /// Build the next snapshot to send from the state of `host`.
+    pub fn next(&mut self, host: &str, asteroids: &Asteroids, players: &[Ship]) -> GameData {
+        let id = self.next_id;
+        self.next_id += 1;
+
+        let base = self
+            .acks
+            .values()
+            .min()
+            .and_then(|min_ack| self.history.iter().find(|snapshot| snapshot.id == *min_ack));
+
+        let gamedata = match base {
+            Some(base)
+                if !self.keyframe_requested
+                    && self.since_keyframe < Snapshots::KEYFRAME_INTERVAL =>
+            {
+                self.since_keyframe += 1;
+                // Peers may have applied any snapshot since the base: what
+                // changed since one of them is sent, and what was in one of
+                // them is removed, even if it was added after the base.
+                let since_base: Vec<&Snapshot> = self
+                    .history
+                    .iter()
+                    .filter(|snapshot| snapshot.id >= base.id)
+                    .collect();
+                let mut changed_asteroids = Asteroids::default();
+                let mut removed_asteroids = Vec::new();
+                let mut removed_players = Vec::new();
+                for snapshot in &since_base {
+                    let (mut changed, removed) = diff_asteroids(asteroids, &snapshot.asteroids);
+                    changed_asteroids
+                        .get_asteroids()
+                        .append(changed.get_asteroids());
+                    for key in removed {
+                        if !removed_asteroids.contains(&key) {
+                            removed_asteroids.push(key);
+                        }
+                    }
+                    for snapshot_ship in &snapshot.players {
+                        let name = snapshot_ship.name();
+                        if !players.iter().any(|ship| ship.name() == name)
+                            && !removed_players.contains(&name)
+                        {
+                            removed_players.push(name);
+                        }
+                    }
+                }
+                GameData {
+                    id,
+                    host: host.to_string(),
+                    base: Some(base.id),
+                    asteroids: changed_asteroids,
+                    removed_asteroids,
+                    players: players
+                        .iter()
+                        .filter(|ship| {
+                            since_base
+                                .iter()
+                                .any(|snapshot| !snapshot.players.contains(ship))
+                        })
+                        .cloned()
+                        .collect(),
+                    removed_players,
+                    inputs: BTreeMap::new(),
+                    round: self.round,
+                    rng: None,
+                }
+            }
+            _ => {
+                self.keyframe_requested = false;
+                self.since_keyframe = 0;
+                GameData {
+                    id,
+                    host: host.to_string(),
+                    base: None,
+                    asteroids: asteroids.clone(),
+                    removed_asteroids: Vec::new(),
+                    players: players.to_vec(),
+                    removed_players: Vec::new(),
+                    inputs: BTreeMap::new(),
+                    round: self.round,
+                    rng: None,
+                }
+            }
+        };
+
+        // Keep only the snapshots that can still be used as a base.
+        self.history.push_back(Snapshot {
+            id,
+            sent_t: clock::now(),
+            asteroids: asteroids.clone(),
+            players: players.to_vec(),
+        });
+        let min_ack = self.acks.values().min().copied().unwrap_or(id);
+        while self.history.len() > Snapshots::HISTORY_SIZE
+            || self
+                .history
+                .front()
+                .is_some_and(|snapshot| snapshot.id < min_ack)
+        {
+            self.history.pop_front();
+        }
+
+        gamedata
+    }
+
+    /// Apply a snapshot received from the host on the local state of player `name`.
+    /// Return false if the snapshot is based on a state this peer does not know.
+    pub fn apply(
+        &mut self,
+        gamedata: GameData,
+        name: &str,
+        asteroids: &mut Asteroids,
+        players: &mut Vec<Ship>,
+    ) -> bool {
+        match gamedata.base {
+            Some(base) if base > self.applied => {
+                log::debug!(
+                    "Cannot apply snapshot {} based on {}, last applied {}",
+                    gamedata.id,
+                    base,
+                    self.applied
+                );
+                return false;
+            }
+            _ => (),
+        }
+
+        let keyframe = gamedata.is_keyframe();
+        // The local ship is owned by this peer unless the host simulates it
+        // from the inputs of this peer.
+        let acked_input = gamedata.inputs.get(name).copied();
+        let host_simulated = acked_input.is_some();
+        synchronize_asteroids(asteroids, gamedata.asteroids);
+        for key in &gamedata.removed_asteroids {
+            asteroids.remove_asteroid(key);
+        }
+
+        if keyframe {
+            // The first keyframe after joining brings the ship spawned by the host.
+            let joining = self.applied == 0;
+            let current_ship = players.iter().find(|ship| ship.name() == name).cloned();
+            *players = gamedata.players;
+            self.confirmed = players
+                .iter()
+                .find(|ship| host_simulated && ship.name() == name)
+                .cloned();
+            self.acked_input = acked_input.filter(|_| self.confirmed.is_some());
+            if let Some(current_ship) = current_ship {
+                match players.iter_mut().find(|ship| ship.name() == name) {
+                    Some(_) if host_simulated || joining => (),
+                    Some(ship) => *ship = current_ship,
+                    // The host does not know this ship yet.
+                    None => players.push(current_ship),
+                }
+            }
+        } else {
+            for opponent in gamedata.players {
+                if opponent.name() == name {
+                    if !host_simulated {
+                        continue;
+                    }
+                    // The acknowledged input goes with the state of the ship
+                    // it gave, a ship missing from the delta is not replayed.
+                    self.confirmed = Some(opponent.clone());
+                    self.acked_input = acked_input;
+                }
+                match players
+                    .iter_mut()
+                    .find(|ship| ship.name() == opponent.name())
+                {
+                    Some(ship) => *ship = opponent,
+                    None => players.push(opponent),
+                }
+            }
+            players.retain(|ship| {
+                ship.name() == name || !gamedata.removed_players.contains(&ship.name())
+            });
+        }
+
+        self.applied = gamedata.id;
+        self.round = gamedata.round;
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use macroquad::prelude::Vec2;
+    use planetoid_core::asteroid::Asteroid;
+    use planetoid_core::collision::Collided;
+
+    fn field() -> Asteroids {
+        let mut asteroids = Asteroids::default();
+        for i in 0..5 {
+            asteroids.add_asteroid(
+                String::from("f1"),
+                Asteroid::new_pos_and_size(i as f32, i as f32, 10.),
+            );
+        }
+        asteroids
+    }
+
+    fn players() -> Vec<Ship> {
+        vec![
+            Ship::new_pos(String::from("host"), Vec2::new(1., 1.)),
+            Ship::new_pos(String::from("guest"), Vec2::new(2., 2.)),
+        ]
+    }
+
+    #[test]
+    fn snapshot_first_is_keyframe_test() {
+        let mut snapshots = Snapshots::new();
+        let gamedata = snapshots.next("host", &field(), &players());
+        assert!(gamedata.is_keyframe());
+        assert_eq!(gamedata.id, 1);
+
+        // Nobody acknowledged anything, so keep sending keyframes.
+        let gamedata = snapshots.next("host", &field(), &players());
+        assert!(gamedata.is_keyframe());
+    }
+
+    #[test]
+    fn snapshot_delta_test() {
+        let mut asteroids = field();
+        let mut players = players();
+        let mut snapshots = Snapshots::new();
+        let keyframe = snapshots.next("host", &asteroids, &players);
+        snapshots.ack("guest", keyframe.id);
+
+        // Nothing changed.
+        let gamedata = snapshots.next("host", &asteroids, &players);
+        assert_eq!(gamedata.base, Some(keyframe.id));
+        assert!(gamedata.asteroids.is_empty());
+        assert!(gamedata.players.is_empty());
+
+        // One asteroid hit, one ship moved, one asteroid created.
+        asteroids
+            .get_asteroids()
+            .get_mut("f1_000000")
+            .unwrap()
+            .set_collided(true);
+        asteroids.add_asteroid(String::from("host"), Asteroid::new_pos_and_size(0., 0., 5.));
+        players[0].set_rot(90.);
+        let mut gamedata = snapshots.next("host", &asteroids, &players);
+        assert_eq!(gamedata.base, Some(keyframe.id));
+        assert_eq!(gamedata.asteroids.get_asteroids().len(), 2);
+        assert_eq!(gamedata.players.len(), 1);
+        assert_eq!(gamedata.players[0].name(), "host");
+
+        // Asteroid garbage collected and guest left.
+        asteroids.remove_asteroid("f1_000000");
+        players.pop();
+        let gamedata2 = snapshots.next("host", &asteroids, &players);
+        assert_eq!(gamedata2.removed_asteroids, vec![String::from("f1_000000")]);
+        assert_eq!(gamedata2.removed_players, vec![String::from("guest")]);
+
+        // Deltas are always computed against the last acknowledged snapshot.
+        assert_eq!(gamedata2.base, Some(keyframe.id));
+    }
+
+    #[test]
+    fn snapshot_delta_since_base_test() {
+        let mut asteroids = field();
+        let mut players = players();
+        let mut host = Snapshots::new();
+        let mut guest = Snapshots::new();
+        let mut guest_asteroids = Asteroids::default();
+        let mut guest_players = Vec::new();
+
+        let keyframe = host.next("host", &asteroids, &players);
+        host.ack("late", keyframe.id);
+        host.ack("guest", keyframe.id);
+        assert!(guest.apply(keyframe, "guest", &mut guest_asteroids, &mut guest_players));
+
+        // The guest sees an asteroid created and a ship turned.
+        asteroids.add_asteroid(String::from("host"), Asteroid::new_pos_and_size(0., 0., 5.));
+        players[0].set_rot(90.);
+        let delta = host.next("host", &asteroids, &players);
+        host.ack("guest", delta.id);
+        assert!(guest.apply(delta, "guest", &mut guest_asteroids, &mut guest_players));
+        assert_eq!(guest_asteroids.get_asteroids().len(), 6);
+
+        // Both are reverted, the next delta is still based on the keyframe
+        // acknowledged by the late peer.
+        asteroids.remove_asteroid("host_000005");
+        players[0].set_rot(0.);
+        let delta = host.next("host", &asteroids, &players);
+        assert_eq!(delta.base, Some(1));
+        assert!(guest.apply(delta, "guest", &mut guest_asteroids, &mut guest_players));
+        assert_eq!(guest_asteroids.get_asteroids().len(), 5);
+        assert_eq!(guest_players[0].rot(), 0.);
+    }
+
+    #[test]
+    fn snapshot_min_ack_test() {
+        let asteroids = field();
+        let players = players();
+        let mut snapshots = Snapshots::new();
+        let gamedata1 = snapshots.next("host", &asteroids, &players);
+        snapshots.ack("guest1", gamedata1.id);
+        let gamedata2 = snapshots.next("host", &asteroids, &players);
+        snapshots.ack("guest2", gamedata2.id);
+
+        let gamedata = snapshots.next("host", &asteroids, &players);
+        assert_eq!(gamedata.base, Some(gamedata1.id));
+
+        // guest1 left, guest2 is the only one.
+        snapshots.remove_peer("guest1");
+        let gamedata = snapshots.next("host", &asteroids, &players);
+        assert_eq!(gamedata.base, Some(gamedata2.id));
+    }
+
+    #[test]
+    fn snapshot_unknown_ack_requests_keyframe_test() {
+        let asteroids = field();
+        let players = players();
+        let mut snapshots = Snapshots::new();
+        let gamedata = snapshots.next("host", &asteroids, &players);
+        snapshots.ack("guest", gamedata.id);
+        assert!(!snapshots.next("host", &asteroids, &players).is_keyframe());
+
+        // A new peer without any snapshot.
+        snapshots.ack("spectator", 0);
+        assert!(snapshots.next("host", &asteroids, &players).is_keyframe());
+    }
+
+    #[test]
+    fn snapshot_ack_rtt_test() {
+        let asteroids = field();
+        let players = players();
+        let mut snapshots = Snapshots::new();
+        let gamedata = snapshots.next("host", &asteroids, &players);
+        assert!(snapshots.ack("guest", gamedata.id).unwrap() >= 0.);
+        // Only the first acknowledgement of a snapshot gives the round trip.
+        assert_eq!(snapshots.ack("guest", gamedata.id), None);
+        assert!(snapshots.ack("spectator", gamedata.id).is_some());
+        assert_eq!(snapshots.ack("spectator", 0), None);
+    }
+
+    #[test]
+    fn snapshot_periodic_keyframe_test() {
+        let asteroids = field();
+        let players = players();
+        let mut snapshots = Snapshots::new();
+        let gamedata = snapshots.next("host", &asteroids, &players);
+        snapshots.ack("guest", gamedata.id);
+
+        let mut keyframes = 0;
+        for _ in 0..(Snapshots::KEYFRAME_INTERVAL + 1) * 2 {
+            let gamedata = snapshots.next("host", &asteroids, &players);
+            if gamedata.is_keyframe() {
+                keyframes += 1;
+            }
+            snapshots.ack("guest", gamedata.id);
+        }
+        assert_eq!(keyframes, 2);
+        assert!(snapshots.history.len() <= Snapshots::HISTORY_SIZE);
+    }
+
+    #[test]
+    fn snapshot_apply_test() {
+        let mut host_asteroids = field();
+        let mut host_players = players();
+        let mut host = Snapshots::new();
+        let mut guest = Snapshots::new();
+        let mut guest_asteroids = Asteroids::default();
+        let mut guest_players = vec![Ship::new_pos(String::from("guest"), Vec2::new(9., 9.))];
+
+        // A delta cannot be applied before a keyframe.
+        host.ack("guest", 0);
+        let keyframe = host.next("host", &host_asteroids, &host_players);
+        host.ack("guest", keyframe.id);
+        let delta = host.next("host", &host_asteroids, &host_players);
+        assert!(!guest.apply(delta, "guest", &mut guest_asteroids, &mut guest_players));
+        assert!(guest_asteroids.is_empty());
+
+        assert!(guest.apply(keyframe, "guest", &mut guest_asteroids, &mut guest_players));
+        assert_eq!(guest.applied(), 1);
+        assert_eq!(guest_asteroids.get_asteroids().len(), 5);
+        assert_eq!(guest_players.len(), 2);
+        // The ship spawned by the host replaces the local one on joining.
+        assert_eq!(guest_players[1], host_players[1]);
+        // Then the local ship is kept.
+        guest_players[1].set_pos(Vec2::new(9., 9.));
+
+        host_asteroids.remove_asteroid("f1_000004");
+        host_players.push(Ship::new_pos(String::from("guest2"), Vec2::new(3., 3.)));
+        host_players[0].set_rot(90.);
+        let delta = host.next("host", &host_asteroids, &host_players);
+        assert!(guest.apply(delta, "guest", &mut guest_asteroids, &mut guest_players));
+        assert_eq!(guest_asteroids.get_asteroids().len(), 4);
+        assert_eq!(guest_players.len(), 3);
+        assert_eq!(guest_players[0], host_players[0]);
+        assert_eq!(guest_players[1].pos(), Vec2::new(9., 9.));
+    }
+
+    #[test]
+    fn snapshot_apply_keeps_unknown_local_ship_test() {
+        let mut host = Snapshots::new();
+        let mut guest = Snapshots::new();
+        let mut guest_asteroids = Asteroids::default();
+        let mut guest_players = vec![Ship::new_pos(String::from("guest2"), Vec2::new(9., 9.))];
+
+        // The host did not receive the hello of guest2 yet.
+        let keyframe = host.next("host", &field(), &players());
+        assert!(guest.apply(keyframe, "guest2", &mut guest_asteroids, &mut guest_players));
+        let names: Vec<String> = guest_players.iter().map(|ship| ship.name()).collect();
+        assert_eq!(names, vec!["host", "guest", "guest2"]);
+    }
+
+    #[test]
+    fn snapshot_apply_host_simulated_ship_test() {
+        let mut host = Snapshots::new();
+        let mut guest = Snapshots::new();
+        let mut guest_asteroids = Asteroids::default();
+        let mut guest_players = vec![Ship::new_pos(String::from("guest"), Vec2::new(9., 9.))];
+        let inputs: BTreeMap<String, u32> = [(String::from("guest"), 3)].into_iter().collect();
+
+        // The host simulates the ship of the guest from its inputs.
+        let mut keyframe = host.next("host", &field(), &players());
+        keyframe.set_inputs(inputs.clone());
+        assert!(guest.apply(keyframe, "guest", &mut guest_asteroids, &mut guest_players));
+        assert_eq!(guest_players, players());
+        assert_eq!(guest.take_confirmed(), Some((3, players()[1].clone())));
+        assert_eq!(guest.take_confirmed(), None);
+
+        host.ack("guest", 1);
+        let mut host_players = players();
+        host_players[1].set_rot(90.);
+        let mut delta = host.next("host", &field(), &host_players);
+        delta.set_inputs(inputs);
+        assert!(!delta.is_keyframe());
+        assert!(guest.apply(delta, "guest", &mut guest_asteroids, &mut guest_players));
+        assert_eq!(guest_players[1].rot(), 90.);
+        assert_eq!(guest.take_confirmed(), Some((3, host_players[1].clone())));
+
+        // The ship did not change, the new acknowledged input is not replayed
+        // on top of an older state.
+        host.ack("guest", 2);
+        guest_players[1].set_rot(95.);
+        let mut delta = host.next("host", &field(), &host_players);
+        delta.set_inputs([(String::from("guest"), 4)].into_iter().collect());
+        assert!(guest.apply(delta, "guest", &mut guest_asteroids, &mut guest_players));
+        assert_eq!(guest.take_confirmed(), None);
+        assert_eq!(guest_players[1].rot(), 95.);
+
+        // Until the ship is sent again with its input.
+        host.ack("guest", 3);
+        host_players[1].set_rot(100.);
+        let mut delta = host.next("host", &field(), &host_players);
+        delta.set_inputs([(String::from("guest"), 5)].into_iter().collect());
+        assert!(guest.apply(delta, "guest", &mut guest_asteroids, &mut guest_players));
+        assert_eq!(guest.take_confirmed(), Some((5, host_players[1].clone())));
+    }
+
+    #[test]
+    fn snapshot_promote_test() {
+        let mut host = Snapshots::new();
+        let mut guest = Snapshots::new();
+        let mut guest_asteroids = Asteroids::default();
+        let mut guest_players = players();
+        for _ in 0..3 {
+            let gamedata = host.next("host", &field(), &players());
+            host.ack("guest", gamedata.id);
+            assert!(guest.apply(gamedata, "guest", &mut guest_asteroids, &mut guest_players));
+        }
+
+        // Snapshots of the new host follow the ones of the previous host.
+        guest.promote();
+        let gamedata = guest.next("host", &guest_asteroids, &guest_players);
+        assert!(gamedata.is_keyframe());
+        assert_eq!(gamedata.id, 4);
+    }
+
+    #[test]
+    fn snapshot_round_test() {
+        let mut host = Snapshots::new();
+        let mut guest = Snapshots::new();
+        let mut guest_asteroids = Asteroids::default();
+        let mut guest_players = Vec::new();
+        host.new_round(10.);
+        host.new_round(20.);
+
+        // A player joining learns the round from the keyframe.
+        let keyframe = host.next("host", &field(), &players());
+        assert!(guest.apply(keyframe, "guest", &mut guest_asteroids, &mut guest_players));
+        assert_eq!(
+            guest.round(),
+            Round {
+                number: 2,
+                started_t: 20.
+            }
+        );
+
+        // A guest taking over goes on with the round.
+        guest.promote();
+        assert_eq!(guest.round().number, 2);
+        guest.new_round(30.);
+        assert_eq!(guest.round().number, 3);
+    }
+}
@@ -0,0 +1,245 @@
+use crate::screen;
+use macroquad::prelude::*;
+use planetoid_core::ship::Ship;
+
+/// View of a spectator, following a player or moving freely over the field.
+///
+/// Tab follows the next player and shift+tab the previous one, cycling back
+/// to the free camera after the last player. The free camera is moved with
+/// the arrows, both views can be zoomed in to watch a large field.
+pub struct SpectatorCamera {
+    /// Name of the player followed, `None` for the free camera.
+    followed: Option<String>,
+    /// Center of the free camera.
+    target: Vec2,
+    zoom: f32,
+    camera: Camera2D,
+}
+
+impl SpectatorCamera {
+    const MIN_ZOOM: f32 = 1.;
+    const MAX_ZOOM: f32 = 4.;
+    const ZOOM_STEP: f32 = 1.25;
+    /// Move of the free camera per frame, in pixels of the screen.
+    const PAN_SPEED: f32 = 10.;
+    const SIDEBAR_WIDTH: f32 = 240.;
+
+    pub fn new() -> Self {
+        Self {
+            followed: None,
+            target: screen::center(),
+            zoom: SpectatorCamera::MIN_ZOOM,
+            camera: Camera2D::from_display_rect(Rect::new(0., 0., screen::WIDTH, screen::HEIGHT)),
+        }
+    }
+
+    /// Handle the keys of the spectator, unless they go to the chat.
+    pub fn update(&mut self, players: &[Ship], typing: bool) {
+        // The followed player left the game.
+        if !self
+            .followed
+            .as_ref()
+            .is_some_and(|name| is_playing(players, name))
+        {
+            self.followed = None;
+        }
+        if typing {
+            return;
+        }
+
+        if is_key_pressed(KeyCode::Tab) {
+            let forward = !is_key_down(KeyCode::LeftShift) && !is_key_down(KeyCode::RightShift);
+            self.cycle(players, forward);
+        }
+
+        let zoom_in = is_key_pressed(KeyCode::Equal) || is_key_pressed(KeyCode::KpAdd);
+        let zoom_out = is_key_pressed(KeyCode::Minus) || is_key_pressed(KeyCode::KpSubtract);
+        let (_, wheel) = mouse_wheel();
+        if zoom_in || wheel > 0. {
+            self.zoom_by(SpectatorCamera::ZOOM_STEP);
+        } else if zoom_out || wheel < 0. {
+            self.zoom_by(1. / SpectatorCamera::ZOOM_STEP);
+        }
+
+        let mut pan = Vec2::ZERO;
+        if is_key_down(KeyCode::Left) {
+            pan.x -= 1.;
+        }
+        if is_key_down(KeyCode::Right) {
+            pan.x += 1.;
+        }
+        if is_key_down(KeyCode::Up) {
+            pan.y -= 1.;
+        }
+        if is_key_down(KeyCode::Down) {
+            pan.y += 1.;
+        }
+        if pan != Vec2::ZERO {
+            // Moving the camera stops following the player, from where the
+            // camera is.
+            if self.followed.take().is_some() {
+                self.target = self.center();
+            }
+            self.target += pan * SpectatorCamera::PAN_SPEED / self.zoom;
+        }
+    }
+
+    /// Follow the next or previous player, the free camera comes between the
+    /// last and the first player.
+    fn cycle(&mut self, players: &[Ship], forward: bool) {
+        let index = self
+            .followed
+            .as_ref()
+            .and_then(|name| players.iter().position(|ship| &ship.name() == name));
+        let next = match (index, forward) {
+            (None, true) => Some(0),
+            (None, false) => players.len().checked_sub(1),
+            (Some(index), true) => Some(index + 1),
+            (Some(index), false) => index.checked_sub(1),
+        }
+        .filter(|next| *next < players.len());
+        if self.followed.is_none() {
+            self.target = self.center();
+        }
+        self.followed = next.map(|index| players[index].name());
+    }
+
+    fn zoom_by(&mut self, factor: f32) {
+        self.zoom =
+            (self.zoom * factor).clamp(SpectatorCamera::MIN_ZOOM, SpectatorCamera::MAX_ZOOM);
+    }
+
+    pub fn followed(&self) -> Option<&str> {
+        self.followed.as_deref()
+    }
+
+    fn center(&self) -> Vec2 {
+        self.camera.target
+    }
+
+    /// Part of the field seen around `center`, kept inside the field.
+    fn view(&self, center: Vec2) -> Rect {
+        let size = Vec2::new(screen::WIDTH, screen::HEIGHT) / self.zoom;
+        let min =
+            (center - size / 2.).clamp(Vec2::ZERO, Vec2::new(screen::WIDTH, screen::HEIGHT) - size);
+        Rect::new(min.x, min.y, size.x, size.y)
+    }
+
+    /// Draw the field seen by the spectator from now on, centered on the
+    /// position of the followed player or on the free camera.
+    pub fn set(&mut self, followed_pos: Option<Vec2>) {
+        let view = self.view(followed_pos.unwrap_or(self.target));
+        self.camera = Camera2D::from_display_rect(view);
+        if self.followed.is_none() {
+            self.target = self.center();
+        }
+        set_camera(&self.camera);
+    }
+
+    /// Name, score and status of the players, on the right of the screen.
+    pub fn draw_sidebar(&self, players: &[Ship]) {
+        let font_size = 22.;
+        let x = screen_width() - SpectatorCamera::SIDEBAR_WIDTH;
+        let height = 70. + players.len() as f32 * font_size;
+        draw_rectangle(
+            x,
+            0.,
+            SpectatorCamera::SIDEBAR_WIDTH,
+            height,
+            Color::new(1., 1., 1., 0.6),
+        );
+
+        let mut y = 25.;
+        let title = match self.followed() {
+            Some(name) => format!("Following {}", name),
+            None => String::from("Free camera"),
+        };
+        draw_text(&title, x + 10., y, font_size, BLACK);
+        for ship in players {
+            y += font_size;
+            let status = if ship.collided() {
+                "destroyed"
+            } else {
+                "alive"
+            };
+            let color = if self.followed() == Some(ship.name().as_str()) {
+                BLUE
+            } else {
+                DARKGRAY
+            };
+            draw_text(
+                &format!("{}  {}  {}", ship.name(), ship.score(), status),
+                x + 10.,
+                y,
+                font_size,
+                color,
+            );
+        }
+        draw_text(
+            "[tab] follow, [+]/[-] zoom",
+            x + 10.,
+            height - 10.,
+            18.,
+            DARKGRAY,
+        );
+    }
+}
+
+fn is_playing(players: &[Ship], name: &str) -> bool {
+    players.iter().any(|ship| ship.name() == name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn players() -> Vec<Ship> {
+        vec![
+            Ship::new_pos(String::from("Uggla"), screen::center()),
+            Ship::new_pos(String::from("Guest"), screen::center()),
+        ]
+    }
+
+    #[test]
+    fn spectator_cycle_test() {
+        let players = players();
+        let mut camera = SpectatorCamera::new();
+        camera.cycle(&players, true);
+        assert_eq!(camera.followed(), Some("Uggla"));
+        camera.cycle(&players, true);
+        assert_eq!(camera.followed(), Some("Guest"));
+        camera.cycle(&players, true);
+        assert_eq!(camera.followed(), None);
+
+        camera.cycle(&players, false);
+        assert_eq!(camera.followed(), Some("Guest"));
+        camera.cycle(&players, false);
+        camera.cycle(&players, false);
+        assert_eq!(camera.followed(), None);
+
+        camera.cycle(&[], true);
+        assert_eq!(camera.followed(), None);
+    }
+
+    #[test]
+    fn spectator_view_test() {
+        let mut camera = SpectatorCamera::new();
+        let field = Rect::new(0., 0., screen::WIDTH, screen::HEIGHT);
+        assert_eq!(camera.view(Vec2::new(10., 10.)), field);
+
+        camera.zoom_by(2.);
+        let view = camera.view(screen::center());
+        assert_eq!(view.w, screen::WIDTH / 2.);
+        assert_eq!(view.point(), screen::center() / 2.);
+        // The view stays inside the field.
+        assert_eq!(
+            camera.view(Vec2::new(10., screen::HEIGHT)).point(),
+            Vec2::new(0., screen::HEIGHT / 2.)
+        );
+
+        camera.zoom_by(100.);
+        assert_eq!(camera.zoom, SpectatorCamera::MAX_ZOOM);
+        camera.zoom_by(0.);
+        assert_eq!(camera.zoom, SpectatorCamera::MIN_ZOOM);
+    }
+}
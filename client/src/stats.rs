@@ -0,0 +1,205 @@
+use std::collections::BTreeMap;
+use std::fmt;
+
+#[derive(Default)]
+struct PeerStats {
+    rtt: Option<f64>,
+    /// Smoothed variation of the round trip time, like RFC 3550.
+    jitter: f64,
+    last_snapshot: Option<u32>,
+    lost: u32,
+    messages: u32,
+    bytes: u64,
+    message_rate: f64,
+    byte_rate: f64,
+}
+
+impl fmt::Display for PeerStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.rtt {
+            Some(rtt) => write!(
+                f,
+                "rtt {:.0}ms jitter {:.0}ms",
+                rtt * 1000.,
+                self.jitter * 1000.
+            )?,
+            None => write!(f, "rtt -")?,
+        }
+        write!(
+            f,
+            ", {:.1} msg/s, {:.1} KiB/s, {} lost",
+            self.message_rate,
+            self.byte_rate / 1024.,
+            self.lost
+        )
+    }
+}
+
+/// Health of the network: round trip time, jitter, lost snapshots and
+/// traffic of each peer, total traffic and malformed messages.
+///
+/// Rates are computed every second.
+pub struct NetStats {
+    peers: BTreeMap<String, PeerStats>,
+    window_t: f64,
+    bytes_in: u64,
+    bytes_in_rate: f64,
+    bytes_out_rate: f64,
+    /// Messages and bytes sent at the start of the window.
+    sent: (u64, u64),
+    message_out_rate: f64,
+    malformed: u32,
+}
+
+impl NetStats {
+    const WINDOW: f64 = 1.;
+
+    pub fn new() -> Self {
+        Self {
+            peers: BTreeMap::new(),
+            window_t: 0.,
+            bytes_in: 0,
+            bytes_in_rate: 0.,
+            bytes_out_rate: 0.,
+            sent: (0, 0),
+            message_out_rate: 0.,
+            malformed: 0,
+        }
+    }
+
+    fn peer(&mut self, name: &str) -> &mut PeerStats {
+        self.peers.entry(name.to_string()).or_default()
+    }
+
+    /// A message received, `from` is unknown for malformed messages.
+    pub fn received(&mut self, from: Option<&str>, bytes: usize) {
+        self.bytes_in += bytes as u64;
+        match from {
+            Some(from) => {
+                let peer = self.peer(from);
+                peer.messages += 1;
+                peer.bytes += bytes as u64;
+            }
+            None => self.malformed += 1,
+        }
+    }
+
+    pub fn rtt(&mut self, name: &str, rtt: f64) {
+        let peer = self.peer(name);
+        if let Some(last_rtt) = peer.rtt {
+            peer.jitter += ((rtt - last_rtt).abs() - peer.jitter) / 16.;
+        }
+        peer.rtt = Some(rtt);
+    }
+
+    /// Snapshots are numbered in sequence, a gap means lost snapshots.
+    pub fn snapshot_received(&mut self, host: &str, id: u32) {
+        let peer = self.peer(host);
+        if let Some(last) = peer.last_snapshot {
+            if id > last + 1 {
+                peer.lost += id - last - 1;
+            }
+        }
+        peer.last_snapshot = Some(id);
+    }
+
+    pub fn remove(&mut self, name: &str) {
+        self.peers.remove(name);
+    }
+
+    /// Compute the rates at the end of a window from the messages and bytes
+    /// `sent` by the connection. Return true, and log the statistics at debug
+    /// level, if a new window starts.
+    pub fn update(&mut self, t: f64, sent: (u64, u64)) -> bool {
+        let elapsed = t - self.window_t;
+        if elapsed < NetStats::WINDOW {
+            return false;
+        }
+        for peer in self.peers.values_mut() {
+            peer.message_rate = peer.messages as f64 / elapsed;
+            peer.byte_rate = peer.bytes as f64 / elapsed;
+            peer.messages = 0;
+            peer.bytes = 0;
+        }
+        self.bytes_in_rate = self.bytes_in as f64 / elapsed;
+        self.message_out_rate = (sent.0 - self.sent.0) as f64 / elapsed;
+        self.bytes_out_rate = (sent.1 - self.sent.1) as f64 / elapsed;
+        self.bytes_in = 0;
+        self.sent = sent;
+        self.window_t = t;
+
+        for line in self.lines() {
+            log::debug!("{}", line);
+        }
+        true
+    }
+
+    /// Text of the overlay, one line for the totals then one per peer.
+    pub fn lines(&self) -> Vec<String> {
+        let mut lines = vec![format!(
+            "in {:.1} KiB/s, out {:.1} msg/s {:.1} KiB/s, {} malformed",
+            self.bytes_in_rate / 1024.,
+            self.message_out_rate,
+            self.bytes_out_rate / 1024.,
+            self.malformed
+        )];
+        lines.extend(
+            self.peers
+                .iter()
+                .map(|(name, peer)| format!("{}: {}", name, peer)),
+        );
+        lines
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn net_stats_rates_test() {
+        let mut stats = NetStats::new();
+        assert!(!stats.update(0.5, (0, 0)));
+        stats.received(Some("Guest"), 1000);
+        stats.received(Some("Guest"), 1048);
+        stats.received(None, 100);
+        assert!(stats.update(2., (10, 4096)));
+        assert_eq!(
+            stats.lines(),
+            vec![
+                "in 1.0 KiB/s, out 5.0 msg/s 2.0 KiB/s, 1 malformed",
+                "Guest: rtt -, 1.0 msg/s, 1.0 KiB/s, 0 lost",
+            ]
+        );
+
+        // Rates are reset every window.
+        assert!(stats.update(3., (10, 4096)));
+        assert_eq!(
+            stats.lines()[1],
+            "Guest: rtt -, 0.0 msg/s, 0.0 KiB/s, 0 lost"
+        );
+
+        stats.remove("Guest");
+        assert_eq!(stats.lines().len(), 1);
+    }
+
+    #[test]
+    fn net_stats_rtt_test() {
+        let mut stats = NetStats::new();
+        stats.rtt("Host", 0.05);
+        stats.rtt("Host", 0.066);
+        assert_eq!(
+            stats.lines()[1],
+            "Host: rtt 66ms jitter 1ms, 0.0 msg/s, 0.0 KiB/s, 0 lost"
+        );
+    }
+
+    #[test]
+    fn net_stats_lost_snapshots_test() {
+        let mut stats = NetStats::new();
+        for id in [4, 5, 8, 9, 12] {
+            stats.snapshot_received("Host", id);
+        }
+        assert!(stats.lines()[1].ends_with("4 lost"));
+    }
+}
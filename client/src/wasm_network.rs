@@ -0,0 +1,174 @@
+use crate::clock;
+use crate::network::{NetworkError, NetworkEvent};
+use std::cell::Cell;
+use std::sync::mpsc::TryRecvError;
+use url::Url;
+
+// Browser WebSocket, implemented by the `planetoid_net` plugin of index.html.
+extern "C" {
+    fn planetoid_ws_connect(url: *const u8, len: usize);
+    fn planetoid_ws_send(data: *const u8, len: usize, binary: bool);
+    fn planetoid_ws_close();
+    fn planetoid_ws_next_event() -> i32;
+    fn planetoid_ws_event_len() -> usize;
+    fn planetoid_ws_event_data(buffer: *mut u8);
+    fn planetoid_query_len() -> usize;
+    fn planetoid_query(buffer: *mut u8);
+}
+
+// Events queued by the plugin.
+const EVENT_NONE: i32 = -1;
+const EVENT_CONNECTED: i32 = 0;
+const EVENT_TEXT: i32 = 1;
+const EVENT_BINARY: i32 = 2;
+const EVENT_DISCONNECTED: i32 = 3;
+
+const RETRY_DELAY_MIN: f64 = 0.5;
+const RETRY_DELAY_MAX: f64 = 8.;
+
+/// Checked by miniquad against the version of the plugin.
+#[no_mangle]
+pub extern "C" fn planetoid_net_crate_version() -> u32 {
+    1 << 16
+}
+
+/// WebSocket frames exchanged with the browser, control frames are handled by
+/// the browser itself.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Message {
+    Text(String),
+    Binary(Vec<u8>),
+}
+
+impl Message {
+    pub fn len(&self) -> usize {
+        match self {
+            Message::Text(text) => text.len(),
+            Message::Binary(data) => data.len(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+enum State {
+    Connecting,
+    Connected,
+    /// Reconnect at the given time.
+    Waiting(f64),
+}
+
+/// Connection to the worker through the browser WebSocket.
+/// Threads are not available, so events are polled by `try_recv` which also
+/// reconnects with an exponential backoff.
+pub struct Connection {
+    url: Url,
+    state: State,
+    retry_delay: f64,
+    /// Messages and bytes sent.
+    sent: Cell<(u64, u64)>,
+}
+
+impl Connection {
+    pub fn open(url: Url) -> Self {
+        connect(&url);
+        Self {
+            url,
+            state: State::Connecting,
+            retry_delay: RETRY_DELAY_MIN,
+            sent: Cell::new((0, 0)),
+        }
+    }
+
+    /// Messages sent while disconnected are dropped, a lost connection is
+    /// reported by `try_recv` as a Disconnected event.
+    pub fn send(&self, msg: Message) {
+        if !matches!(self.state, State::Connected) {
+            return;
+        }
+        let (data, binary) = match &msg {
+            Message::Text(text) => (text.as_bytes(), false),
+            Message::Binary(data) => (data.as_slice(), true),
+        };
+        unsafe { planetoid_ws_send(data.as_ptr(), data.len(), binary) };
+        let (count, bytes) = self.sent.get();
+        self.sent.set((count + 1, bytes + data.len() as u64));
+    }
+
+    /// Messages and bytes sent since the connection was opened.
+    pub fn sent(&self) -> (u64, u64) {
+        self.sent.get()
+    }
+
+    pub fn try_recv(&mut self) -> Result<NetworkEvent, TryRecvError> {
+        if let State::Waiting(retry_t) = self.state {
+            if clock::now() < retry_t {
+                return Err(TryRecvError::Empty);
+            }
+            connect(&self.url);
+            self.state = State::Connecting;
+        }
+
+        match unsafe { planetoid_ws_next_event() } {
+            EVENT_CONNECTED => {
+                log::info!("Connected to the server");
+                self.state = State::Connected;
+                self.retry_delay = RETRY_DELAY_MIN;
+                Ok(NetworkEvent::Connected)
+            }
+            EVENT_TEXT => Ok(NetworkEvent::Message(Message::Text(
+                String::from_utf8_lossy(&event_data()).into_owned(),
+            ))),
+            EVENT_BINARY => Ok(NetworkEvent::Message(Message::Binary(event_data()))),
+            EVENT_DISCONNECTED => {
+                log::info!("Reconnecting in {}s.", self.retry_delay);
+                self.state = State::Waiting(clock::now() + self.retry_delay);
+                self.retry_delay = (self.retry_delay * 2.).min(RETRY_DELAY_MAX);
+                // The browser does not give the reason of a WebSocket error.
+                Ok(NetworkEvent::Disconnected(NetworkError::Disconnected))
+            }
+            EVENT_NONE => Err(TryRecvError::Empty),
+            event => {
+                log::error!("Unknown WebSocket event {}", event);
+                Err(TryRecvError::Empty)
+            }
+        }
+    }
+
+    /// Close the connection, messages already sent are flushed by the browser.
+    pub fn close(self) {
+        unsafe { planetoid_ws_close() };
+    }
+}
+
+fn connect(url: &Url) {
+    log::debug!("Connect to WebSocket url {}", url);
+    let url = url.as_str();
+    unsafe { planetoid_ws_connect(url.as_ptr(), url.len()) };
+}
+
+/// Data of the current event.
+fn event_data() -> Vec<u8> {
+    let mut data = vec![0; unsafe { planetoid_ws_event_len() }];
+    unsafe { planetoid_ws_event_data(data.as_mut_ptr()) };
+    data
+}
+
+/// Command line arguments built from the query parameters of the page,
+/// `?mode=guest&name=Uggla&god` gives `planetoid --mode guest --name Uggla --god`.
+pub fn args() -> Vec<String> {
+    let mut query = vec![0; unsafe { planetoid_query_len() }];
+    unsafe { planetoid_query(query.as_mut_ptr()) };
+    let query = String::from_utf8_lossy(&query);
+
+    let mut args = vec![String::from("planetoid")];
+    for (key, value) in url::form_urlencoded::parse(query.trim_start_matches('?').as_bytes()) {
+        args.push(format!("--{}", key));
+        if !value.is_empty() {
+            args.push(value.into_owned());
+        }
+    }
+    args
+}
@@ -1,6 +1,7 @@
 use crate::collision::Collided;
-use crate::screen;
-use macroquad::prelude::*;
+use crate::rng::Rng;
+use crate::world::{World, TICK_RATE};
+use glam::Vec2;
 use serde::de::{self, Deserializer, MapAccess, Visitor};
 use serde::ser::{SerializeStruct, Serializer};
 use serde::{Deserialize, Serialize};
@@ -8,17 +9,17 @@ use std::cmp::Ordering;
 use std::collections::BTreeMap;
 use std::fmt;
 
-#[derive(Serialize, Deserialize, Clone, Debug)]
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
 pub struct Asteroids {
     count: usize,
     asteroids: BTreeMap<String, Asteroid>,
 }
 
 impl Asteroids {
-    pub fn generate_field(name: String, number: usize) -> Self {
+    pub fn generate_field(name: String, number: usize, world: &mut World) -> Self {
         let mut asteroids = BTreeMap::new();
         for item in 0..number {
-            let asteroid = Asteroid::new();
+            let asteroid = Asteroid::new(world);
             asteroids.insert(format!("{}_{:06}", name, item), asteroid);
         }
 
@@ -34,7 +35,6 @@ impl Asteroids {
         self.count += 1;
     }
 
-    #[cfg(not(target_arch = "wasm32"))]
     pub fn refresh_last_updated(&mut self, last_updated: f64) {
         for asteroid in self.asteroids.values_mut() {
             asteroid.set_last_updated(last_updated);
@@ -48,9 +48,29 @@ impl Asteroids {
     pub fn is_empty(&self) -> bool {
         self.asteroids.is_empty()
     }
+
+    pub fn remove_asteroid(&mut self, key: &str) {
+        self.asteroids.remove(key);
+    }
+
+    /// Position the farthest from the asteroids among a few random ones, for
+    /// a ship joining a running round.
+    pub fn safe_position(&self, world: &mut World) -> Vec2 {
+        const CANDIDATES: usize = 16;
+        let clearance = |pos: Vec2| {
+            self.asteroids
+                .values()
+                .filter(|asteroid| !asteroid.collided)
+                .map(|asteroid| (asteroid.pos - pos).length() - asteroid.size)
+                .fold(f32::INFINITY, f32::min)
+        };
+        (0..CANDIDATES)
+            .map(|_| world.random_pos())
+            .max_by(|pos1, pos2| clearance(*pos1).total_cmp(&clearance(*pos2)))
+            .unwrap()
+    }
 }
 
-#[cfg(not(target_arch = "wasm32"))]
 pub fn synchronize_asteroids(field1: &mut Asteroids, field2: Asteroids) {
     for (key_field2, value_field2) in &field2.asteroids {
         match field1.asteroids.get(key_field2) {
@@ -72,6 +92,37 @@ pub fn synchronize_asteroids(field1: &mut Asteroids, field2: Asteroids) {
     }
 }
 
+/// Return the asteroids of `field` that are new or updated compared to `base`
+/// and the keys of the asteroids of `base` that are not in `field` anymore.
+pub fn diff_asteroids(field: &Asteroids, base: &Asteroids) -> (Asteroids, Vec<String>) {
+    let mut changed = BTreeMap::new();
+    for (key, value) in &field.asteroids {
+        match base.asteroids.get(key) {
+            Some(base_value)
+                if base_value.last_updated() == value.last_updated()
+                    && base_value.collided() == value.collided() => {}
+            _ => {
+                changed.insert(key.clone(), value.clone());
+            }
+        }
+    }
+
+    let removed = base
+        .asteroids
+        .keys()
+        .filter(|key| !field.asteroids.contains_key(*key))
+        .cloned()
+        .collect();
+
+    (
+        Asteroids {
+            count: field.count,
+            asteroids: changed,
+        },
+        removed,
+    )
+}
+
 #[derive(Debug)]
 pub struct Asteroid {
     pos: Vec2,
@@ -85,29 +136,37 @@ pub struct Asteroid {
 }
 
 impl Asteroid {
-    pub fn new() -> Self {
+    /// Pixels per second, asteroids are faster once split.
+    const SPEED: f32 = 60.;
+    /// Degrees per second.
+    const MAX_ROT_SPEED: f32 = 120.;
+
+    /// Asteroid on a circle around the center of the arena.
+    pub fn new(world: &mut World) -> Self {
+        let arena_size = world.width.min(world.height);
+        let rng = &mut world.rng;
         Self {
-            pos: screen::center()
-                + Vec2::new(rand::gen_range(-1., 1.), rand::gen_range(-1., 1.)).normalize()
-                    * screen_width().min(screen_height())
+            pos: Vec2::new(world.width / 2., world.height / 2.)
+                + Vec2::new(rng.gen_range(-1., 1.), rng.gen_range(-1., 1.)).normalize()
+                    * arena_size
                     / 2.,
-            vel: Vec2::new(rand::gen_range(-1., 1.), rand::gen_range(-1., 1.)),
+            vel: Vec2::new(rng.gen_range(-1., 1.), rng.gen_range(-1., 1.)) * Asteroid::SPEED,
             rot: 0.,
-            rot_speed: rand::gen_range(-2., 2.),
-            size: screen_width().min(screen_height()) / 10.,
+            rot_speed: rng.gen_range(-Asteroid::MAX_ROT_SPEED, Asteroid::MAX_ROT_SPEED),
+            size: arena_size / 10.,
             sides: 8,
             collided: false,
             last_updated: 0.,
         }
     }
 
-    #[allow(dead_code)]
+    /// Still asteroid at `x`, `y`.
     pub fn new_pos_and_size(x: f32, y: f32, size: f32) -> Self {
         Self {
             pos: Vec2::new(x, y),
-            vel: Vec2::new(rand::gen_range(-1., 1.), rand::gen_range(-1., 1.)),
+            vel: Vec2::ZERO,
             rot: 0.,
-            rot_speed: rand::gen_range(-2., 2.),
+            rot_speed: 0.,
             size,
             sides: 8,
             collided: false,
@@ -122,14 +181,15 @@ impl Asteroid {
         size: f32,
         sides: u8,
         last_updated: f64,
+        rng: &mut Rng,
     ) -> Vec<Asteroid> {
         let mut new_asteroids = Vec::new();
 
         let asteroid1 = Self {
             pos,
-            vel: Vec2::new(vely, -velx).normalize() * rand::gen_range(1., 3.),
-            rot: rand::gen_range(0., 360.),
-            rot_speed: rand::gen_range(-2., 2.),
+            vel: Vec2::new(vely, -velx).normalize() * rng.gen_range(1., 3.) * Asteroid::SPEED,
+            rot: rng.gen_range(0., 360.),
+            rot_speed: rng.gen_range(-Asteroid::MAX_ROT_SPEED, Asteroid::MAX_ROT_SPEED),
             size: size * 0.8,
             sides: sides - 1,
             collided: false,
@@ -138,9 +198,9 @@ impl Asteroid {
 
         let asteroid2 = Self {
             pos,
-            vel: Vec2::new(-vely, velx).normalize(),
-            rot: rand::gen_range(0., 360.),
-            rot_speed: rand::gen_range(-2., 2.),
+            vel: Vec2::new(-vely, velx).normalize() * Asteroid::SPEED,
+            rot: rng.gen_range(0., 360.),
+            rot_speed: rng.gen_range(-Asteroid::MAX_ROT_SPEED, Asteroid::MAX_ROT_SPEED),
             size: size * 0.8,
             sides: sides - 1,
             collided: false,
@@ -152,16 +212,20 @@ impl Asteroid {
         new_asteroids
     }
 
-    pub fn update_pos(&mut self) {
-        self.pos += self.vel;
-        self.pos = screen::wrap_around(&self.pos);
-        self.rot += self.rot_speed;
+    /// Move the asteroid by one tick.
+    pub fn update_pos(&mut self, world: &World) {
+        self.pos += self.vel / TICK_RATE as f32;
+        self.pos = world.wrap_around(self.pos);
+        self.rot += self.rot_speed / TICK_RATE as f32;
+    }
+
+    pub fn rot(&self) -> f32 {
+        self.rot
     }
 
-    pub fn draw(&self) {
-        draw_poly_lines(
-            self.pos.x, self.pos.y, self.sides, self.size, self.rot, 2., BLACK,
-        )
+    pub fn set_pose(&mut self, pos: Vec2, rot: f32) {
+        self.pos = pos;
+        self.rot = rot;
     }
 
     pub fn sides(&self) -> u8 {
@@ -227,6 +291,8 @@ impl<'de> Deserialize<'de> for Asteroid {
             Sides,
             Collided,
             LastUpdated,
+            /// Field of a newer build.
+            Ignore,
         }
 
         impl<'de> Deserialize<'de> for Field {
@@ -258,7 +324,7 @@ impl<'de> Deserialize<'de> for Asteroid {
                             "sides" => Ok(Field::Sides),
                             "collided" => Ok(Field::Collided),
                             "last_updated" => Ok(Field::LastUpdated),
-                            _ => Err(de::Error::unknown_field(value, FIELDS)),
+                            _ => Ok(Field::Ignore),
                         }
                     }
                 }
@@ -338,6 +404,9 @@ impl<'de> Deserialize<'de> for Asteroid {
                             }
                             last_updated = Some(map.next_value()?);
                         }
+                        Field::Ignore => {
+                            map.next_value::<de::IgnoredAny>()?;
+                        }
                     }
                 }
                 let pos = pos.ok_or_else(|| de::Error::missing_field("pos"))?;
@@ -439,21 +508,19 @@ mod tests {
 
     #[test]
     fn gen_rand_test() {
-        // This is not a real test just a snippet to check how the quad-rand crate is working
+        // This is not a real test just a snippet to check how the random generator is working
         // If the random generator is feed with the same seed, it gives random numbers, but the
         // numbers are the same between 2 runs.
         // Using the UNIX_EPOCH as the seed avoid to use the same seed between runs.
-        rand::srand(
-            SystemTime::now()
-                .duration_since(SystemTime::UNIX_EPOCH)
-                .unwrap()
-                .as_secs(),
-        );
-
-        for _i in 0..10 {
-            dbg!(rand::rand());
-        }
-        dbg!(rand::rand());
+        let seed = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        let numbers = |seed: u64| {
+            let mut rng = Rng::new(seed);
+            (0..10).map(|_| rng.gen_range(0., 1.)).collect::<Vec<f32>>()
+        };
+        assert_eq!(numbers(seed), numbers(seed));
     }
 
     #[test]
@@ -631,6 +698,45 @@ mod tests {
         assert!(field1.asteroids.get("f1_000001").unwrap() == &asteroid2);
     }
 
+    #[test]
+    fn asteroid_diff_test() {
+        let mut asteroid1 = Asteroid::new_pos_and_size(0., 0., 10.);
+        let mut asteroid2 = Asteroid::new_pos_and_size(0., 0., 10.);
+        let mut asteroid3 = Asteroid::new_pos_and_size(0., 0., 10.);
+        asteroid1.set_last_updated(0.0);
+        asteroid2.set_last_updated(2.0);
+        asteroid3.set_last_updated(3.0);
+
+        let mut base = Asteroids::default();
+        base.add_asteroid("f1".to_string(), asteroid1.clone());
+        base.add_asteroid("f1".to_string(), asteroid2.clone());
+        base.add_asteroid("f1".to_string(), asteroid3.clone());
+
+        let mut field = base.clone();
+        // Asteroid 1 unchanged, 2 updated, 3 removed and a new one.
+        field
+            .asteroids
+            .get_mut("f1_000001")
+            .unwrap()
+            .set_last_updated(5.0);
+        field.remove_asteroid("f1_000002");
+        field.add_asteroid("f2".to_string(), asteroid1.clone());
+
+        let (changed, removed) = diff_asteroids(&field, &base);
+        assert_eq!(changed.asteroids.len(), 2);
+        assert_eq!(
+            changed.asteroids.get("f1_000001").unwrap().last_updated(),
+            5.0
+        );
+        assert!(changed.asteroids.contains_key("f2_000003"));
+        assert_eq!(removed, vec!["f1_000002".to_string()]);
+        assert_eq!(changed.count, field.count);
+
+        let (changed, removed) = diff_asteroids(&base, &base);
+        assert!(changed.is_empty());
+        assert!(removed.is_empty());
+    }
+
     #[test]
     fn asteroid_refresh_last_updated_test() {
         let asteroid1 = Asteroid::new_pos_and_size(0., 0., 10.);
@@ -696,4 +802,54 @@ mod tests {
         assert!(field1.asteroids.get("f1_000000").unwrap() == &asteroid1);
         assert!(field1.asteroids.get("f1_000001").unwrap() == &asteroid2);
     }
+
+    #[test]
+    fn safe_position_test() {
+        let mut field = Asteroids::default();
+        let mut world = World::new(1024., 768., 42);
+        let center = world.center();
+        field.add_asteroid(
+            String::from("f1"),
+            Asteroid::new_pos_and_size(center.x, center.y, 200.),
+        );
+        for _ in 0..10 {
+            let pos = field.safe_position(&mut world);
+            assert!((pos - center).length() > 200.);
+            assert!(pos.x <= world.width && pos.y <= world.height);
+        }
+    }
+
+    #[test]
+    fn generate_field_seed_test() {
+        // Asteroids are equal when updated at the same time, compare their motion.
+        let motions = |asteroids: Vec<Asteroid>| -> Vec<(Vec2, Vec2, f32)> {
+            asteroids
+                .iter()
+                .map(|asteroid| (asteroid.pos, asteroid.vel, asteroid.rot_speed))
+                .collect()
+        };
+        let field = |seed: u64| {
+            let mut world = World::new(1024., 768., seed);
+            let field = Asteroids::generate_field(String::from("f1"), 5, &mut world);
+            motions(field.asteroids.into_values().collect())
+        };
+        assert_eq!(field(42).len(), 5);
+        assert_eq!(field(42), field(42));
+        assert_ne!(field(42), field(43));
+
+        // Splits are replayed too.
+        let split = |seed: u64| {
+            motions(Asteroid::new_split(
+                Vec2::new(512., 384.),
+                1.,
+                0.,
+                50.,
+                8,
+                1.,
+                &mut Rng::new(seed),
+            ))
+        };
+        assert_eq!(split(42), split(42));
+        assert_ne!(split(42), split(43));
+    }
 }
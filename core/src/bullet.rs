@@ -1,5 +1,6 @@
 use crate::collision::Collided;
-use macroquad::prelude::*;
+use crate::world::TICK_RATE;
+use glam::Vec2;
 use serde::de::{self, Deserializer, MapAccess, Visitor};
 use serde::ser::{SerializeStruct, Serializer};
 use serde::{Deserialize, Serialize};
@@ -25,12 +26,9 @@ impl Bullet {
         }
     }
 
-    pub fn draw(&self) {
-        draw_circle(self.pos.x, self.pos.y, 2., BLACK);
-    }
-
+    /// Move the bullet by one tick, `vel` is per second.
     pub fn update_pos(&mut self) {
-        self.pos += self.vel;
+        self.pos += self.vel / TICK_RATE as f32;
     }
 
     pub fn shot_at(&self) -> f64 {
@@ -86,6 +84,8 @@ impl<'de> Deserialize<'de> for Bullet {
             ShotAt,
             Size,
             Collided,
+            /// Field of a newer build.
+            Ignore,
         }
 
         impl<'de> Deserialize<'de> for Field {
@@ -112,7 +112,7 @@ impl<'de> Deserialize<'de> for Bullet {
                             "shot_at" => Ok(Field::ShotAt),
                             "size" => Ok(Field::Size),
                             "collided" => Ok(Field::Collided),
-                            _ => Err(de::Error::unknown_field(value, FIELDS)),
+                            _ => Ok(Field::Ignore),
                         }
                     }
                 }
@@ -171,6 +171,9 @@ impl<'de> Deserialize<'de> for Bullet {
                             }
                             collided = Some(map.next_value()?);
                         }
+                        Field::Ignore => {
+                            map.next_value::<de::IgnoredAny>()?;
+                        }
                     }
                 }
                 let pos = pos.ok_or_else(|| de::Error::missing_field("pos"))?;
@@ -205,6 +208,16 @@ impl Clone for Bullet {
     }
 }
 
+impl PartialEq for Bullet {
+    fn eq(&self, other: &Self) -> bool {
+        self.pos == other.pos
+            && self.vel == other.vel
+            && self.shot_at == other.shot_at
+            && self.size == other.size
+            && self.collided == other.collided
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -230,4 +243,14 @@ mod tests {
         assert_eq!(bullet.size, deserialize.size);
         assert_eq!(bullet.collided, deserialize.collided);
     }
+
+    #[test]
+    fn bullet_speed_test() {
+        // The speed is per second, whatever the number of ticks per second.
+        let mut bullet = Bullet::new(Vec2::new(10., 20.), Vec2::new(120., -60.), 1., false);
+        for _ in 0..TICK_RATE as usize {
+            bullet.update_pos();
+        }
+        assert!((bullet.pos() - Vec2::new(130., -40.)).length() < 0.01);
+    }
 }
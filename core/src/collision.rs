@@ -1,8 +1,10 @@
-use macroquad::prelude::*;
+use glam::Vec2;
 
 use crate::{
     asteroid::{Asteroid, Asteroids},
+    rng::Rng,
     ship::Ship,
+    world::World,
 };
 
 pub trait Collided {
@@ -14,19 +16,34 @@ pub fn is_collided<A: Collided, B: Collided>(obj1: &A, obj2: &B) -> bool {
     (obj1.pos() - obj2.pos()).length() < obj1.size() + obj2.size()
 }
 
+/// `simulated` are the ships whose hits split the asteroids on this peer: the
+/// local ship unless the host simulates it, and on the host the ships driven
+/// by the inputs of the guests. The hits happen at the time of the `world`,
+/// the time of the host.
 pub fn manage_collisions(
+    world: &mut World,
     players: &mut [Ship],
     asteroids: &mut Asteroids,
     name: String,
+    simulated: &[String],
     god: bool,
     mode: &str,
-    frame_t: f64,
-    sync_t: f64,
 ) {
+    let frame_t = world.t;
     let mut opponents = players.to_vec();
     for ship in players.iter_mut() {
-        ship_vs_asteroids(ship, asteroids, name.clone(), god, mode, sync_t);
-        ship_vs_opponents(ship, &mut opponents);
+        let simulated = simulated.contains(&ship.name());
+        ship_vs_asteroids(
+            ship,
+            asteroids,
+            name.clone(),
+            simulated,
+            god,
+            mode,
+            frame_t,
+            &mut world.rng,
+        );
+        ship_vs_opponents(ship, &mut opponents, frame_t);
 
         // Garbage collect bullets every 1.5s (bullets can almost cross the screen).
         // This needs to be done only on the local ship as frame_t make sens
@@ -38,9 +55,9 @@ pub fn manage_collisions(
 
         // Garbage collect asteroids collided every 200ms.
         // This is mandatory to keep the messages small and limit the bandwidth.
-        asteroids.get_asteroids().retain(|_key, value| {
-            (value.last_updated() + 0.2) > (get_time() - sync_t) || !value.collided()
-        });
+        asteroids
+            .get_asteroids()
+            .retain(|_key, value| (value.last_updated() + 0.2) > frame_t || !value.collided());
     }
 
     for ship_index in 0..players.len() {
@@ -50,24 +67,28 @@ pub fn manage_collisions(
     }
 }
 
+#[allow(clippy::too_many_arguments)]
 fn ship_vs_asteroids(
     ship: &mut Ship,
     asteroids: &mut Asteroids,
     name: String,
+    simulated: bool,
     god: bool,
     mode: &str,
-    sync_t: f64,
+    frame_t: f64,
+    rng: &mut Rng,
 ) {
     let mut new_asteroids = Vec::new();
     for asteroid in asteroids.get_asteroids().values_mut() {
-        if is_collided(asteroid, ship) && !god && mode != "spectator" {
+        if is_collided(asteroid, ship) && !god && mode != "spectator" && !ship.is_protected(frame_t)
+        {
             ship.set_collided(true);
         }
-        ship_bullet_vs_asteroid(ship, asteroid, &mut new_asteroids, sync_t);
+        ship_bullet_vs_asteroid(ship, asteroid, &mut new_asteroids, frame_t, rng);
     }
 
-    // Send new asteroids created only for this player.
-    if ship.name() == name {
+    // Send new asteroids created only by the ships simulated here.
+    if simulated {
         for asteroid in new_asteroids {
             asteroids.add_asteroid(name.clone(), asteroid);
         }
@@ -78,13 +99,16 @@ fn ship_bullet_vs_asteroid(
     ship: &mut Ship,
     asteroid: &mut Asteroid,
     new_asteroids: &mut Vec<Asteroid>,
-    sync_t: f64,
+    frame_t: f64,
+    rng: &mut Rng,
 ) {
+    let mut hit = false;
     for bullet in ship.bullets.iter_mut() {
         if !bullet.collided() && !asteroid.collided() && is_collided(asteroid, bullet) {
             asteroid.set_collided(true);
-            asteroid.set_last_updated(get_time() - sync_t);
+            asteroid.set_last_updated(frame_t);
             bullet.set_collided(true);
+            hit = true;
             // Split asteroid into 2 smaller parts except if we have a square.
             if asteroid.sides() > 4 {
                 *new_asteroids = Asteroid::new_split(
@@ -94,16 +118,21 @@ fn ship_bullet_vs_asteroid(
                     asteroid.size(),
                     asteroid.sides(),
                     asteroid.last_updated(),
+                    rng,
                 );
             }
             break;
         }
     }
+    if hit {
+        ship.add_score(1);
+    }
 }
 
-fn ship_vs_opponents(ship: &mut Ship, opponents: &mut [Ship]) {
+fn ship_vs_opponents(ship: &mut Ship, opponents: &mut [Ship], frame_t: f64) {
     for opponent in opponents.iter_mut() {
-        if opponent.name() != ship.name() {
+        // Bullets go through the ships joining the round.
+        if opponent.name() != ship.name() && !opponent.is_protected(frame_t) {
             ship_bullet_vs_opponents(ship, opponent);
         }
     }
@@ -0,0 +1,330 @@
+use crate::ship::Ship;
+use crate::world::World;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, VecDeque};
+
+/// Controls of a ship during one tick of the simulation.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+pub struct Input {
+    /// Increased by one every tick by the guest, 0 before being recorded.
+    pub seq: u32,
+    pub thrust: bool,
+    /// -1 to rotate left, 1 to rotate right.
+    pub rotate: i8,
+    pub fire: bool,
+    /// Time of the tick on the guest.
+    pub t: f64,
+}
+
+impl Input {
+    pub fn new(thrust: bool, rotate: i8, fire: bool, t: f64) -> Self {
+        Self {
+            seq: 0,
+            thrust,
+            rotate,
+            fire,
+            t,
+        }
+    }
+}
+
+/// Inputs of the local player recorded by a guest until they are sent to
+/// the host, and until the host applied them to predict the local ship.
+pub struct InputLog {
+    next_seq: u32,
+    unsent: Vec<Input>,
+    unacked: VecDeque<Input>,
+}
+
+impl InputLog {
+    /// Inputs replayed at most, 2s of ticks.
+    const MAX_UNACKED: usize = 120;
+
+    pub fn new() -> Self {
+        Self {
+            next_seq: 1,
+            unsent: Vec::new(),
+            unacked: VecDeque::new(),
+        }
+    }
+
+    pub fn record(&mut self, mut input: Input) -> Input {
+        input.seq = self.next_seq;
+        self.next_seq += 1;
+        self.unsent.push(input);
+        self.unacked.push_back(input);
+        if self.unacked.len() > InputLog::MAX_UNACKED {
+            self.unacked.pop_front();
+        }
+        input
+    }
+
+    /// Replay on `ship`, the state sent by the host after it applied the
+    /// input `acked`, the inputs the host did not apply yet.
+    pub fn reconcile(&mut self, acked: u32, ship: &mut Ship, world: &World) {
+        while self.unacked.front().is_some_and(|input| input.seq <= acked) {
+            self.unacked.pop_front();
+        }
+        for input in &self.unacked {
+            ship.replay(input, world);
+        }
+    }
+
+    pub fn take_unsent(&mut self) -> Vec<Input> {
+        std::mem::take(&mut self.unsent)
+    }
+
+    /// Forget the inputs not sent yet, sequence numbers keep increasing so the
+    /// host does not drop the next ones.
+    pub fn clear(&mut self) {
+        self.unsent.clear();
+        self.unacked.clear();
+    }
+}
+
+impl Default for InputLog {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+struct InputQueue {
+    pending: VecDeque<Input>,
+    /// Last input received.
+    last_seq: u32,
+    /// Last input applied.
+    applied: u32,
+    fire_t: f64,
+}
+
+/// Inputs received by the host, the ships of the guests are simulated by
+/// applying one input per tick.
+///
+/// Guests sending their whole ship instead are not in the queues.
+pub struct InputQueues {
+    queues: HashMap<String, InputQueue>,
+}
+
+impl InputQueues {
+    /// Inputs kept while the host is late, 0.5s of ticks.
+    const MAX_PENDING: usize = 30;
+    /// Same fire rate as the local player.
+    const FIRE_INTERVAL: f64 = 0.1;
+
+    pub fn new() -> Self {
+        Self {
+            queues: HashMap::new(),
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.queues.clear();
+    }
+
+    /// Queue the inputs of `name`, the ones already received are ignored.
+    pub fn push(&mut self, name: &str, inputs: Vec<Input>) {
+        let queue = self
+            .queues
+            .entry(name.to_string())
+            .or_insert_with(|| InputQueue {
+                pending: VecDeque::new(),
+                last_seq: 0,
+                applied: 0,
+                fire_t: f64::MIN,
+            });
+        for input in inputs {
+            if input.seq > queue.last_seq {
+                queue.last_seq = input.seq;
+                queue.pending.push_back(input);
+            }
+        }
+        while queue.pending.len() > InputQueues::MAX_PENDING {
+            let input = queue.pending.pop_front().unwrap();
+            log::debug!("{} is late, dropping input {}", name, input.seq);
+        }
+    }
+
+    pub fn remove(&mut self, name: &str) {
+        self.queues.remove(name);
+    }
+
+    /// Ships driven by inputs, simulated by the host.
+    pub fn names(&self) -> impl Iterator<Item = &String> {
+        self.queues.keys()
+    }
+
+    /// Last input applied by the host for each ship driven by inputs, 0 if
+    /// none yet.
+    pub fn applied(&self) -> BTreeMap<String, u32> {
+        self.queues
+            .iter()
+            .map(|(name, queue)| (name.clone(), queue.applied))
+            .collect()
+    }
+
+    /// Apply the next input of each ship driven by inputs, between
+    /// `Ship::slow_down` and `Ship::update_pos`, at the time of the tick of
+    /// the host.
+    pub fn apply(&mut self, players: &mut [Ship], world: &World) {
+        for ship in players.iter_mut() {
+            let queue = match self.queues.get_mut(&ship.name()) {
+                Some(queue) => queue,
+                None => continue,
+            };
+            let mut input = match queue.pending.pop_front() {
+                Some(input) => input,
+                None => continue,
+            };
+            queue.applied = input.seq;
+            // Do not trust the guest to limit its fire rate with its own time.
+            input.t = world.t;
+            if input.fire {
+                if input.t - queue.fire_t < InputQueues::FIRE_INTERVAL {
+                    input.fire = false;
+                } else {
+                    queue.fire_t = input.t;
+                }
+            }
+            ship.control(&input);
+            ship.bullets
+                .retain(|bullet| bullet.shot_at() + 1.5 > input.t);
+        }
+    }
+}
+
+impl Default for InputQueues {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::world::TICK;
+    use glam::Vec2;
+
+    fn players() -> Vec<Ship> {
+        vec![
+            Ship::new_pos(String::from("Uggla"), Vec2::new(10., 20.)),
+            Ship::new_pos(String::from("Guest"), Vec2::new(30., 40.)),
+        ]
+    }
+
+    #[test]
+    fn input_log_test() {
+        let mut log = InputLog::new();
+        assert_eq!(log.record(Input::new(true, 0, false, 1.)).seq, 1);
+        assert_eq!(log.record(Input::new(false, 1, false, 1.1)).seq, 2);
+        let unsent = log.take_unsent();
+        assert_eq!(unsent.len(), 2);
+        assert!(unsent[0].thrust);
+        assert!(log.take_unsent().is_empty());
+
+        log.record(Input::new(false, 0, true, 1.2));
+        log.clear();
+        assert!(log.take_unsent().is_empty());
+        assert_eq!(log.record(Input::new(false, 0, true, 1.3)).seq, 4);
+    }
+
+    #[test]
+    fn input_log_reconcile_test() {
+        let world = World::new(1024., 768., 42);
+        let mut log = InputLog::new();
+        let mut host_ship = Ship::new_pos(String::from("Guest"), Vec2::new(30., 40.));
+        let mut guest_ship = host_ship.clone();
+        let inputs: Vec<Input> = (0..10)
+            .map(|i| log.record(Input::new(i % 2 == 0, 1, i == 4, i as f64 * 0.016)))
+            .collect();
+        for input in &inputs {
+            guest_ship.replay(input, &world);
+        }
+
+        // The host applied the first 6 inputs, the guest replays the others on
+        // top of the state of the host and predicts the same ship.
+        for input in &inputs[..6] {
+            host_ship.replay(input, &world);
+        }
+        log.reconcile(6, &mut host_ship, &world);
+        assert_eq!(host_ship, guest_ship);
+        assert_eq!(host_ship.bullets.len(), 1);
+
+        // Acknowledged inputs are not replayed again.
+        let mut ship = guest_ship.clone();
+        log.reconcile(10, &mut ship, &world);
+        assert_eq!(ship, guest_ship);
+    }
+
+    #[test]
+    fn input_queues_apply_test() {
+        let mut queues = InputQueues::new();
+        let mut players = players();
+        let mut log = InputLog::new();
+        let inputs = vec![
+            log.record(Input::new(false, 1, false, 1.)),
+            log.record(Input::new(false, 1, false, 1.1)),
+        ];
+        queues.push("Guest", inputs.clone());
+        // Inputs received twice are applied once.
+        queues.push("Guest", inputs);
+        assert_eq!(queues.names().collect::<Vec<_>>(), vec!["Guest"]);
+
+        let world = World::new(1024., 768., 42);
+        for _ in 0..3 {
+            queues.apply(&mut players, &world);
+        }
+        assert_eq!(players[0].rot(), 0.);
+        assert_eq!(players[1].rot(), 10.);
+        assert_eq!(queues.applied()["Guest"], 2);
+        assert!(!queues.applied().contains_key("Uggla"));
+    }
+
+    #[test]
+    fn input_queues_fire_rate_test() {
+        let mut queues = InputQueues::new();
+        let mut players = players();
+        let mut log = InputLog::new();
+        let mut world = World::new(1024., 768., 42);
+        let inputs = (0..4)
+            .map(|_| log.record(Input::new(false, 0, true, 0.)))
+            .collect();
+        queues.push("Guest", inputs);
+        for t in [1., 1.05, 1.15, 1.3] {
+            world.t = t;
+            queues.apply(&mut players, &world);
+        }
+        assert_eq!(players[1].bullets.len(), 3);
+
+        // The time of the guest is ignored, it cannot fire every tick.
+        let inputs = (0..4)
+            .map(|i| log.record(Input::new(false, 0, true, 10. + i as f64)))
+            .collect();
+        queues.push("Guest", inputs);
+        world.t = 2.;
+        for _ in 0..4 {
+            world.t += TICK;
+            queues.apply(&mut players, &world);
+        }
+        assert_eq!(players[1].bullets.len(), 4);
+        assert!(players[1]
+            .bullets
+            .iter()
+            .all(|bullet| bullet.shot_at() < 10.));
+    }
+
+    #[test]
+    fn input_queues_late_guest_test() {
+        let mut queues = InputQueues::new();
+        let mut players = players();
+        let mut log = InputLog::new();
+        let inputs = (0..40)
+            .map(|i| log.record(Input::new(false, 0, false, i as f64)))
+            .collect();
+        queues.push("Guest", inputs);
+        queues.apply(&mut players, &World::new(1024., 768., 42));
+        assert_eq!(queues.applied()["Guest"], 11);
+
+        queues.remove("Guest");
+        assert_eq!(queues.names().count(), 0);
+    }
+}
@@ -0,0 +1,13 @@
+//! Game logic of Planetoid, without window, sound nor network.
+//!
+//! The simulation runs by fixed ticks in a `World`, its arena, clock and
+//! random generator. The clients, the server and the tests drive the same
+//! entities and collisions.
+
+pub mod asteroid;
+pub mod bullet;
+pub mod collision;
+pub mod input;
+pub mod rng;
+pub mod ship;
+pub mod world;
@@ -0,0 +1,82 @@
+use serde::{Deserialize, Serialize};
+
+/// Random generator of the simulation, owned by the game state.
+///
+/// It is the PCG generator behind `macroquad::rand`, but seeded for each game
+/// instead of shared by the whole program, so a seed always generates the
+/// same asteroid fields and splits. Its state is serialized so a peer joining
+/// a running game continues the sequence of the host.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Rng {
+    seed: u64,
+    state: u64,
+}
+
+impl Rng {
+    const INCREMENT: u64 = 1442695040888963407;
+    const MULTIPLIER: u64 = 6364136223846793005;
+
+    pub fn new(seed: u64) -> Self {
+        let mut rng = Self { seed, state: 0 };
+        rng.next_u32();
+        rng.state = rng.state.wrapping_add(seed);
+        rng.next_u32();
+        rng
+    }
+
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    fn next_u32(&mut self) -> u32 {
+        let state = self.state;
+        self.state = state
+            .wrapping_mul(Rng::MULTIPLIER)
+            .wrapping_add(Rng::INCREMENT);
+        let xorshifted = (((state >> 18) ^ state) >> 27) as u32;
+        xorshifted.rotate_right((state >> 59) as u32)
+    }
+
+    /// Number between `low` and `high`.
+    pub fn gen_range(&mut self, low: f32, high: f32) -> f32 {
+        let r = self.next_u32() as f32 / u32::MAX as f32;
+        low + (high - low) * r
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rng_seed_test() {
+        let mut rng1 = Rng::new(42);
+        let mut rng2 = Rng::new(42);
+        let mut rng3 = Rng::new(43);
+        let numbers1: Vec<f32> = (0..10).map(|_| rng1.gen_range(0., 1.)).collect();
+        let numbers2: Vec<f32> = (0..10).map(|_| rng2.gen_range(0., 1.)).collect();
+        let numbers3: Vec<f32> = (0..10).map(|_| rng3.gen_range(0., 1.)).collect();
+        assert_eq!(numbers1, numbers2);
+        assert_ne!(numbers1, numbers3);
+        assert_eq!(rng1.seed(), 42);
+    }
+
+    #[test]
+    fn rng_range_test() {
+        let mut rng = Rng::new(7);
+        for _ in 0..1000 {
+            let number = rng.gen_range(-2., 3.);
+            assert!((-2. ..=3.).contains(&number));
+        }
+    }
+
+    #[test]
+    fn rng_state_test() {
+        let mut rng1 = Rng::new(42);
+        rng1.gen_range(0., 1.);
+        let json = serde_json::to_string(&rng1).unwrap();
+        let mut rng2: Rng = serde_json::from_str(&json).unwrap();
+        assert_eq!(rng1, rng2);
+        assert_eq!(rng1.gen_range(0., 1.), rng2.gen_range(0., 1.));
+    }
+}
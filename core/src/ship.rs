@@ -1,6 +1,7 @@
-use crate::screen;
+use crate::input::Input;
+use crate::world::{World, TICK_RATE};
 use crate::{bullet::Bullet, collision::Collided};
-use macroquad::prelude::*;
+use glam::Vec2;
 use serde::de::{self, Deserializer, MapAccess, Visitor};
 use serde::ser::{SerializeStruct, Serializer};
 use serde::{Deserialize, Serialize};
@@ -15,92 +16,112 @@ pub struct Ship {
     rot: f32,
     size: f32,
     collided: bool,
+    score: u32,
+    /// Time of the host until which the ship cannot be destroyed, after
+    /// joining a running round.
+    protected_until: f64,
     pub bullets: Vec<Bullet>,
 }
 
 impl Ship {
     pub const HEIGHT: f32 = 25.;
     pub const BASE: f32 = 22.;
-    const DACC_FACTOR: f32 = 30.;
-    const ACC_FACTOR: f32 = 3.;
-    pub fn new(name: String) -> Self {
+    /// Speeds are per second, the ship moves by a fraction of them every
+    /// tick of the simulation.
+    const MAX_SPEED: f32 = 600.;
+    const ACCELERATION: f32 = 1200.;
+    /// Share of its speed the ship loses per second without thrust.
+    const DRAG: f32 = 2.;
+    /// Degrees per second.
+    const ROT_SPEED: f32 = 300.;
+    const BULLET_SPEED: f32 = 420.;
+    /// Seconds of protection of a ship joining a running round.
+    pub const PROTECTION: f64 = 3.;
+    /// Ship at a random position of the arena.
+    pub fn new(name: String, world: &mut World) -> Self {
+        Ship::new_pos(name, world.random_pos())
+    }
+
+    pub fn new_pos(name: String, pos: Vec2) -> Self {
         Self {
             name,
-            // pos: screen::center(),
-            // Temporary for debugging
-            pos: Vec2::new(
-                rand::gen_range(0., screen_width()),
-                rand::gen_range(0., screen_height()),
-            ),
+            pos,
             vel: Vec2::new(0., 0.),
             acc: Vec2::new(0., 0.),
             rot: 0.,
             size: Ship::HEIGHT / 3.,
             collided: false,
+            score: 0,
+            protected_until: 0.,
             bullets: Vec::new(),
         }
     }
 
+    /// Ship of a player joining a running round at `pos`, protected until
+    /// it had the time to move away from the asteroids coming at it.
+    pub fn spawn(name: String, pos: Vec2, t: f64) -> Self {
+        Self {
+            protected_until: t + Ship::PROTECTION,
+            ..Ship::new_pos(name, pos)
+        }
+    }
+
+    pub fn is_protected(&self, t: f64) -> bool {
+        t < self.protected_until
+    }
+
     pub fn rotation(&self) -> f32 {
         self.rot.to_radians()
     }
 
-    pub fn draw(&self, color: Color) {
-        let v1 = Vec2::new(
-            self.pos.x + self.rotation().sin() * Ship::HEIGHT / 2.,
-            self.pos.y - self.rotation().cos() * Ship::HEIGHT / 2.,
-        );
-        let v2 = Vec2::new(
-            self.pos.x
-                - self.rotation().cos() * Ship::BASE / 2.
-                - self.rotation().sin() * Ship::HEIGHT / 2.,
-            self.pos.y - self.rotation().sin() * Ship::BASE / 2.
-                + self.rotation().cos() * Ship::HEIGHT / 2.,
-        );
-        let v3 = Vec2::new(
-            self.pos.x + self.rotation().cos() * Ship::BASE / 2.
-                - self.rotation().sin() * Ship::HEIGHT / 2.,
-            self.pos.y
-                + self.rotation().sin() * Ship::BASE / 2.
-                + self.rotation().cos() * Ship::HEIGHT / 2.,
-        );
-        let v1_2 = Vec2::new(
-            self.pos.x + self.rotation().sin() * Ship::HEIGHT / 4.,
-            self.pos.y - self.rotation().cos() * Ship::HEIGHT / 4.,
-        );
-        let v2_2 = Vec2::new(
-            self.pos.x
-                - self.rotation().cos() * Ship::BASE / 4.
-                - self.rotation().sin() * Ship::HEIGHT / 4.,
-            self.pos.y - self.rotation().sin() * Ship::BASE / 4.
-                + self.rotation().cos() * Ship::HEIGHT / 4.,
-        );
-        let v3_2 = Vec2::new(
-            self.pos.x + self.rotation().cos() * Ship::BASE / 4.
-                - self.rotation().sin() * Ship::HEIGHT / 4.,
-            self.pos.y
-                + self.rotation().sin() * Ship::BASE / 4.
-                + self.rotation().cos() * Ship::HEIGHT / 4.,
-        );
-        draw_triangle_lines(v1, v2, v3, 2., color);
-        draw_triangle_lines(v1_2, v2_2, v3_2, 2., color);
-    }
+    // The physics of the ship only depends on its state and on the controls,
+    // so the host and the guest get the same result from the same inputs and
+    // a guest can replay its inputs on top of the state sent by the host.
 
     pub fn slow_down(&mut self) {
-        self.acc = -self.vel() / Ship::DACC_FACTOR;
+        self.acc = -self.vel() * Ship::DRAG;
     }
 
     pub fn accelerate(&mut self) {
-        self.acc = Vec2::new(self.rotation().sin(), -self.rotation().cos()) / Ship::ACC_FACTOR;
+        self.acc = Vec2::new(self.rotation().sin(), -self.rotation().cos()) * Ship::ACCELERATION;
+    }
+
+    /// Apply the controls of a tick, between `slow_down` and `update_pos`.
+    /// Bullets are shot at the time of the input.
+    pub fn control(&mut self, input: &Input) {
+        if self.collided {
+            return;
+        }
+        if input.thrust {
+            self.accelerate();
+        }
+        if input.fire {
+            self.shoot(input.t);
+        }
+        self.set_rot(
+            self.rot() + Ship::ROT_SPEED / TICK_RATE as f32 * input.rotate.signum() as f32,
+        );
+    }
+
+    /// Move the ship by one tick.
+    pub fn update_pos(&mut self, world: &World) {
+        self.vel += self.acc / TICK_RATE as f32;
+        if self.vel.length() > Ship::MAX_SPEED {
+            self.vel = self.vel.normalize() * Ship::MAX_SPEED;
+        }
+        self.pos += self.vel / TICK_RATE as f32;
+        self.pos = world.wrap_around(self.pos);
     }
 
-    pub fn update_pos(&mut self) {
-        self.vel += self.acc;
-        if self.vel.length() > 10. {
-            self.vel = self.vel.normalize() * 10.;
+    /// Simulate a whole tick of the ship and its bullets with `input`, like
+    /// the game loop does for the local ship.
+    pub fn replay(&mut self, input: &Input, world: &World) {
+        self.slow_down();
+        self.control(input);
+        self.update_pos(world);
+        for bullet in self.bullets.iter_mut() {
+            bullet.update_pos();
         }
-        self.pos += self.vel;
-        self.pos = screen::wrap_around(&self.pos);
     }
 
     pub fn name(&self) -> String {
@@ -119,6 +140,10 @@ impl Ship {
         self.rot = rot;
     }
 
+    pub fn set_pos(&mut self, pos: Vec2) {
+        self.pos = pos;
+    }
+
     pub fn collided(&self) -> bool {
         self.collided
     }
@@ -127,11 +152,19 @@ impl Ship {
         self.collided = collided;
     }
 
+    pub fn score(&self) -> u32 {
+        self.score
+    }
+
+    pub fn add_score(&mut self, points: u32) {
+        self.score += points;
+    }
+
     pub fn shoot(&mut self, frame_t: f64) {
         let rot_vec = Vec2::new(self.rotation().sin(), -self.rotation().cos());
         self.bullets.push(Bullet::new(
             self.pos() + rot_vec * Ship::HEIGHT / 2.,
-            rot_vec * 7.,
+            rot_vec * Ship::BULLET_SPEED,
             frame_t,
             false,
         ));
@@ -153,7 +186,7 @@ impl Serialize for Ship {
     where
         S: Serializer,
     {
-        let mut state = serializer.serialize_struct("Ship", 8)?;
+        let mut state = serializer.serialize_struct("Ship", 10)?;
         state.serialize_field("name", &self.name)?;
         state.serialize_field("pos", &vec![&self.pos[0], &self.pos[1]])?;
         state.serialize_field("vel", &vec![&self.vel[0], &self.vel[1]])?;
@@ -161,6 +194,8 @@ impl Serialize for Ship {
         state.serialize_field("rot", &self.rot)?;
         state.serialize_field("size", &self.size)?;
         state.serialize_field("collided", &self.collided)?;
+        state.serialize_field("score", &self.score)?;
+        state.serialize_field("protected_until", &self.protected_until)?;
         state.serialize_field("bullets", &self.bullets)?;
         state.end()
     }
@@ -179,7 +214,11 @@ impl<'de> Deserialize<'de> for Ship {
             Rot,
             Size,
             Collided,
+            Score,
+            ProtectedUntil,
             Bullets,
+            /// Field of a newer build.
+            Ignore,
         }
 
         impl<'de> Deserialize<'de> for Field {
@@ -194,7 +233,7 @@ impl<'de> Deserialize<'de> for Ship {
 
                     fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
                         formatter.write_str(
-                            "`name`, `pos`, `vel`, `acc`, `rot`, `size`, `collided` or `bullets`",
+                            "`name`, `pos`, `vel`, `acc`, `rot`, `size`, `collided`, `score`, `protected_until` or `bullets`",
                         )
                     }
 
@@ -210,8 +249,10 @@ impl<'de> Deserialize<'de> for Ship {
                             "rot" => Ok(Field::Rot),
                             "size" => Ok(Field::Size),
                             "collided" => Ok(Field::Collided),
+                            "score" => Ok(Field::Score),
+                            "protected_until" => Ok(Field::ProtectedUntil),
                             "bullets" => Ok(Field::Bullets),
-                            _ => Err(de::Error::unknown_field(value, FIELDS)),
+                            _ => Ok(Field::Ignore),
                         }
                     }
                 }
@@ -240,6 +281,8 @@ impl<'de> Deserialize<'de> for Ship {
                 let mut rot = None;
                 let mut size = None;
                 let mut collided = None;
+                let mut score = None;
+                let mut protected_until = None;
                 let mut bullets: Option<Vec<Bullet>> = None;
                 while let Some(key) = map.next_key()? {
                     match key {
@@ -285,12 +328,27 @@ impl<'de> Deserialize<'de> for Ship {
                             }
                             collided = Some(map.next_value()?);
                         }
+                        Field::Score => {
+                            if score.is_some() {
+                                return Err(de::Error::duplicate_field("score"));
+                            }
+                            score = Some(map.next_value()?);
+                        }
+                        Field::ProtectedUntil => {
+                            if protected_until.is_some() {
+                                return Err(de::Error::duplicate_field("protected_until"));
+                            }
+                            protected_until = Some(map.next_value()?);
+                        }
                         Field::Bullets => {
                             if bullets.is_some() {
                                 return Err(de::Error::duplicate_field("bullets"));
                             }
                             bullets = Some(map.next_value()?);
                         }
+                        Field::Ignore => {
+                            map.next_value::<de::IgnoredAny>()?;
+                        }
                     }
                 }
                 let name = name.ok_or_else(|| de::Error::missing_field("name"))?;
@@ -300,6 +358,9 @@ impl<'de> Deserialize<'de> for Ship {
                 let rot = rot.ok_or_else(|| de::Error::missing_field("rot"))?;
                 let size = size.ok_or_else(|| de::Error::missing_field("size"))?;
                 let collided = collided.ok_or_else(|| de::Error::missing_field("collided"))?;
+                let score = score.ok_or_else(|| de::Error::missing_field("score"))?;
+                // Older builds do not protect the ships.
+                let protected_until = protected_until.unwrap_or(0.);
                 let bullets = bullets.ok_or_else(|| de::Error::missing_field("bullets"))?;
                 Ok(Ship {
                     name,
@@ -309,13 +370,24 @@ impl<'de> Deserialize<'de> for Ship {
                     rot,
                     size,
                     collided,
+                    score,
+                    protected_until,
                     bullets,
                 })
             }
         }
 
         const FIELDS: &[&str] = &[
-            "name", "pos", "vel", "acc", "rot", "size", "collided", "bullets",
+            "name",
+            "pos",
+            "vel",
+            "acc",
+            "rot",
+            "size",
+            "collided",
+            "score",
+            "protected_until",
+            "bullets",
         ];
         deserializer.deserialize_struct("Ship", FIELDS, ShipVisitor)
     }
@@ -331,11 +403,28 @@ impl Clone for Ship {
             rot: self.rot,
             size: self.size,
             collided: self.collided,
+            score: self.score,
+            protected_until: self.protected_until,
             bullets: self.bullets.clone(),
         }
     }
 }
 
+impl PartialEq for Ship {
+    fn eq(&self, other: &Self) -> bool {
+        self.name == other.name
+            && self.pos == other.pos
+            && self.vel == other.vel
+            && self.acc == other.acc
+            && self.rot == other.rot
+            && self.size == other.size
+            && self.collided == other.collided
+            && self.score == other.score
+            && self.protected_until == other.protected_until
+            && self.bullets == other.bullets
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -358,6 +447,8 @@ mod tests {
             rot: 1.,
             size: 1.,
             collided: false,
+            score: 42,
+            protected_until: 0.,
             bullets,
         };
         let serialize = serde_json::to_string(&ship).unwrap();
@@ -372,6 +463,8 @@ mod tests {
         assert_eq!(ship.rot, deserialize.rot);
         assert_eq!(ship.size, deserialize.size);
         assert_eq!(ship.collided, deserialize.collided);
+        assert_eq!(ship.score, deserialize.score);
+        assert_eq!(ship.protected_until, deserialize.protected_until);
         assert_eq!(ship.bullets[0].pos(), deserialize.bullets[0].pos());
         assert_eq!(ship.bullets[0].vel(), deserialize.bullets[0].vel());
         assert_eq!(ship.bullets[0].shot_at(), deserialize.bullets[0].shot_at());
@@ -390,6 +483,27 @@ mod tests {
         );
     }
 
+    #[test]
+    fn ship_deserialize_unknown_field_test() {
+        // Fields added by newer builds are ignored.
+        let json = r#"{"name":"Uggla","pos":[1.0,1.0],"vel":[0.0,0.0],"acc":[0.0,0.0],"rot":0.0,"size":1.0,"collided":false,"score":0,"bullets":[{"pos":[1.0,1.0],"vel":[2.0,2.0],"shot_at":5.0,"size":2.0,"collided":false,"color":"red"}],"shield":{"level":3}}"#;
+        let ship: Ship = serde_json::from_str(json).unwrap();
+        assert_eq!(ship.name(), "Uggla");
+        assert_eq!(ship.bullets.len(), 1);
+        assert!(serde_json::from_str::<Ship>(r#"{"name":"Uggla","shield":3}"#).is_err());
+        // Ships of older builds are not protected.
+        assert!(!ship.is_protected(0.));
+    }
+
+    #[test]
+    fn ship_spawn_protection_test() {
+        let ship = Ship::spawn(String::from("Guest"), Vec2::new(1., 2.), 10.);
+        assert_eq!(ship.pos(), Vec2::new(1., 2.));
+        assert!(ship.is_protected(10.));
+        assert!(ship.is_protected(10. + Ship::PROTECTION - 0.1));
+        assert!(!ship.is_protected(10. + Ship::PROTECTION));
+    }
+
     #[test]
     fn ship_clone_test() {
         let mut bullets: Vec<Bullet> = Vec::new();
@@ -405,6 +519,8 @@ mod tests {
             rot: 1.,
             size: 1.,
             collided: false,
+            score: 42,
+            protected_until: 0.,
             bullets,
         };
 
@@ -417,6 +533,7 @@ mod tests {
         assert_eq!(ship.rot, ship_clone.rot);
         assert_eq!(ship.size, ship_clone.size);
         assert_eq!(ship.collided, ship_clone.collided);
+        assert_eq!(ship.score, ship_clone.score);
         assert_eq!(ship.bullets[0].pos(), ship_clone.bullets[0].pos());
         assert_eq!(ship.bullets[0].vel(), ship_clone.bullets[0].vel());
         assert_eq!(ship.bullets[0].shot_at(), ship_clone.bullets[0].shot_at());
@@ -0,0 +1,220 @@
+use crate::asteroid::Asteroids;
+use crate::collision::manage_collisions;
+use crate::input::{Input, InputQueues};
+use crate::rng::Rng;
+use crate::ship::Ship;
+use glam::Vec2;
+
+/// Steps of the simulation per second, whatever the frame rate.
+pub const TICK_RATE: f64 = 60.;
+/// Seconds simulated by each step.
+pub const TICK: f64 = 1. / TICK_RATE;
+
+/// Arena, clock and random generator of the simulation.
+///
+/// The arena wraps around: an entity leaving it on one side comes back on
+/// the other side.
+pub struct World {
+    pub width: f32,
+    pub height: f32,
+    /// Time of the host at the current tick.
+    pub t: f64,
+    pub rng: Rng,
+}
+
+impl World {
+    pub fn new(width: f32, height: f32, seed: u64) -> Self {
+        Self {
+            width,
+            height,
+            t: 0.,
+            rng: Rng::new(seed),
+        }
+    }
+
+    pub fn center(&self) -> Vec2 {
+        Vec2::new(self.width / 2., self.height / 2.)
+    }
+
+    pub fn wrap_around(&self, pos: Vec2) -> Vec2 {
+        let mut wrapped_pos = pos;
+        if wrapped_pos.x > self.width {
+            wrapped_pos.x = 0.;
+        }
+        if wrapped_pos.x < 0. {
+            wrapped_pos.x = self.width;
+        }
+        if wrapped_pos.y > self.height {
+            wrapped_pos.y = 0.;
+        }
+        if wrapped_pos.y < 0. {
+            wrapped_pos.y = self.height;
+        }
+        wrapped_pos
+    }
+
+    pub fn random_pos(&mut self) -> Vec2 {
+        Vec2::new(
+            self.rng.gen_range(0., self.width),
+            self.rng.gen_range(0., self.height),
+        )
+    }
+}
+
+/// Move the ships, their bullets and the asteroids by one tick, once the
+/// controls of the ships are applied.
+pub fn update(world: &World, players: &mut [Ship], asteroids: &mut Asteroids) {
+    for ship in players.iter_mut() {
+        ship.update_pos(world);
+        for bullet in ship.bullets.iter_mut() {
+            bullet.update_pos();
+        }
+    }
+
+    for asteroid in asteroids.get_asteroids().values_mut() {
+        asteroid.update_pos(world);
+    }
+}
+
+/// Peer running the simulation, a player or a server.
+pub struct Peer<'a> {
+    /// Name of the local player, the name of the host on a server.
+    pub name: &'a str,
+    pub mode: &'a str,
+    /// The local ship never collides.
+    pub god: bool,
+    /// The collisions of the local ship are resolved by this peer, a guest
+    /// sending its inputs leaves them to the host.
+    pub simulates_own_ship: bool,
+}
+
+/// Simulate one tick at `t`, the time of the host.
+///
+/// The local ship follows `input`, the ships of the guests follow their
+/// queued `inputs` on the host, then every entity moves and the collisions
+/// are resolved. Return true when the round is over.
+pub fn step(
+    world: &mut World,
+    players: &mut [Ship],
+    asteroids: &mut Asteroids,
+    inputs: &mut InputQueues,
+    input: Option<&Input>,
+    peer: &Peer,
+    t: f64,
+) -> bool {
+    world.t = t;
+    for ship in players.iter_mut() {
+        ship.slow_down();
+    }
+    if let Some(input) = input {
+        for ship in players.iter_mut().filter(|ship| ship.name() == peer.name) {
+            ship.control(input);
+        }
+    }
+    inputs.apply(players, world);
+
+    update(world, players, asteroids);
+
+    let mut simulated: Vec<String> = inputs.names().cloned().collect();
+    if peer.simulates_own_ship {
+        simulated.push(peer.name.to_string());
+    }
+    manage_collisions(
+        world,
+        players,
+        asteroids,
+        peer.name.to_string(),
+        &simulated,
+        peer.god,
+        peer.mode,
+    );
+
+    // The game waits for players, it is not over before they join.
+    asteroids.is_empty() || (!players.is_empty() && players.iter().all(|ship| ship.collided()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asteroid::Asteroid;
+    use crate::collision::Collided;
+
+    #[test]
+    fn world_wrap_around_test() {
+        let world = World::new(100., 50., 42);
+        assert_eq!(world.center(), Vec2::new(50., 25.));
+        assert_eq!(world.wrap_around(Vec2::new(10., 20.)), Vec2::new(10., 20.));
+        assert_eq!(world.wrap_around(Vec2::new(101., -1.)), Vec2::new(0., 50.));
+        assert_eq!(world.wrap_around(Vec2::new(-1., 51.)), Vec2::new(100., 0.));
+    }
+
+    #[test]
+    fn world_step_test() {
+        let mut world = World::new(100., 50., 42);
+        let mut players = vec![Ship::new_pos(String::from("Uggla"), Vec2::new(10., 20.))];
+        let mut asteroids = Asteroids::default();
+        asteroids.add_asteroid(String::from("f1"), Asteroid::new_pos_and_size(80., 40., 5.));
+        let peer = Peer {
+            name: "Uggla",
+            mode: "host",
+            god: false,
+            simulates_own_ship: true,
+        };
+        let input = Input::new(false, 1, false, 0.);
+
+        assert!(!step(
+            &mut world,
+            &mut players,
+            &mut asteroids,
+            &mut InputQueues::new(),
+            Some(&input),
+            &peer,
+            1.,
+        ));
+        assert_eq!(world.t, 1.);
+        assert_eq!(players[0].rot(), 5.);
+
+        // The round waits for players, it is over without asteroid.
+        assert!(!step(
+            &mut world,
+            &mut Vec::new(),
+            &mut asteroids,
+            &mut InputQueues::new(),
+            None,
+            &peer,
+            2.,
+        ));
+        asteroids.get_asteroids().clear();
+        assert!(step(
+            &mut world,
+            &mut players,
+            &mut asteroids,
+            &mut InputQueues::new(),
+            None,
+            &peer,
+            2.,
+        ));
+    }
+
+    #[test]
+    fn world_update_test() {
+        let mut world = World::new(100., 50., 42);
+        let mut players = vec![Ship::new(String::from("Uggla"), &mut world)];
+        let pos = players[0].pos();
+        assert!(pos.x <= 100. && pos.y <= 50.);
+        players[0].shoot(0.);
+        let mut asteroids = Asteroids::default();
+        asteroids.add_asteroid(String::from("f1"), Asteroid::new_pos_and_size(1., 2., 10.));
+
+        // Without thrust, only the bullet moves.
+        for _ in 0..TICK_RATE as usize {
+            update(&world, &mut players, &mut asteroids);
+        }
+        assert_eq!(players[0].pos(), pos);
+        assert!(players[0].bullets[0].pos().y < pos.y - 400.);
+        assert_eq!(
+            asteroids.get_asteroids()["f1_000000"].pos(),
+            Vec2::new(1., 2.)
+        );
+    }
+}
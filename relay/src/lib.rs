@@ -0,0 +1,529 @@
+use percent_encoding::percent_decode_str;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::io;
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, sleep, JoinHandle};
+use std::time::{Duration, Instant};
+use tungstenite::handshake::server::{ErrorResponse, Request, Response};
+use tungstenite::http::StatusCode;
+use tungstenite::{Message, WebSocket};
+
+/// Room of the `/gamedata/{username}` endpoint shared with the worker.
+pub const DEFAULT_ROOM: &str = "default";
+
+/// Connected players by username, with a session id to not remove a newer
+/// session of a player that reconnected with the same name.
+type Room = HashMap<String, (u64, Sender<Message>)>;
+
+/// Rooms by name, a room is removed when its last player leaves.
+type Rooms = Arc<Mutex<HashMap<String, Room>>>;
+
+/// Room open on the relay, as listed by the `/lobby` endpoint.
+#[derive(Serialize, Debug, PartialEq)]
+pub struct RoomInfo {
+    pub name: String,
+    pub players: Vec<String>,
+}
+
+/// Message sent by the `/lobby` endpoint when the rooms change.
+#[derive(Serialize, Debug, PartialEq)]
+pub struct RoomList {
+    pub rooms: Vec<RoomInfo>,
+}
+
+/// Interval between two checks of the rooms listed by the lobby.
+const LOBBY_REFRESH: Duration = Duration::from_millis(200);
+
+/// WebSocket relay broadcasting every message received on
+/// `/gamedata/{room}/{username}` to all the sessions of the same room.
+///
+/// `/gamedata/{username}` joins the [`DEFAULT_ROOM`], like the worker
+/// `GameData` endpoint. `/lobby` lists the rooms and their players, as a
+/// JSON [`RoomList`] sent on connection and every time it changes.
+pub struct Relay {
+    listener: TcpListener,
+    rooms: Rooms,
+    running: Arc<AtomicBool>,
+}
+
+/// Handle on a relay running in its own thread.
+pub struct RelayHandle {
+    running: Arc<AtomicBool>,
+    thread: JoinHandle<io::Result<()>>,
+}
+
+impl RelayHandle {
+    /// Stop accepting connections and drop all the sessions.
+    pub fn stop(self) -> io::Result<()> {
+        self.running.store(false, Ordering::SeqCst);
+        self.thread
+            .join()
+            .map_err(|_| io::Error::other("Relay thread panicked"))?
+    }
+}
+
+impl Relay {
+    pub fn bind<A: ToSocketAddrs>(addr: A) -> io::Result<Self> {
+        let listener = TcpListener::bind(addr)?;
+        listener.set_nonblocking(true)?;
+        Ok(Self {
+            listener,
+            rooms: Arc::new(Mutex::new(HashMap::new())),
+            running: Arc::new(AtomicBool::new(true)),
+        })
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    pub fn spawn(self) -> RelayHandle {
+        let running = self.running.clone();
+        RelayHandle {
+            running,
+            thread: thread::spawn(move || self.run()),
+        }
+    }
+
+    /// Accept connections until the relay is stopped.
+    pub fn run(self) -> io::Result<()> {
+        log::info!("Relay listening on {}", self.local_addr()?);
+        let next_id = AtomicU64::new(0);
+        while self.running.load(Ordering::SeqCst) {
+            match self.listener.accept() {
+                Ok((stream, addr)) => {
+                    log::debug!("Connection from {}", addr);
+                    // Accepted streams may inherit the non blocking mode of the listener.
+                    stream.set_nonblocking(false)?;
+                    let rooms = self.rooms.clone();
+                    let running = self.running.clone();
+                    let id = next_id.fetch_add(1, Ordering::SeqCst);
+                    thread::spawn(move || {
+                        if let Err(e) = handle_session(stream, id, rooms, running) {
+                            log::warn!("{}", e);
+                        }
+                    });
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    sleep(Duration::from_millis(10));
+                }
+                Err(e) => log::error!("Cannot accept connection: {}", e),
+            }
+        }
+        log::info!("Relay stopped");
+        Ok(())
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum Endpoint {
+    GameData { room: String, name: String },
+    Lobby,
+}
+
+fn endpoint(path: &str) -> Option<Endpoint> {
+    if path == "/lobby" {
+        return Some(Endpoint::Lobby);
+    }
+    room_and_username(path).map(|(room, name)| Endpoint::GameData { room, name })
+}
+
+/// Return the room and username of a `/gamedata/{room}/{username}` or
+/// `/gamedata/{username}` path.
+fn room_and_username(path: &str) -> Option<(String, String)> {
+    let decode = |segment: &str| percent_decode_str(segment).decode_utf8_lossy().into_owned();
+    let segments: Vec<&str> = path.strip_prefix("/gamedata/")?.split('/').collect();
+    if segments.iter().any(|segment| segment.is_empty()) {
+        return None;
+    }
+    match segments[..] {
+        [name] => Some((String::from(DEFAULT_ROOM), decode(name))),
+        [room, name] => Some((decode(room), decode(name))),
+        _ => None,
+    }
+}
+
+// Error types are the ones of the tungstenite handshake callback.
+#[allow(clippy::result_large_err)]
+fn handle_session(
+    stream: TcpStream,
+    id: u64,
+    rooms: Rooms,
+    running: Arc<AtomicBool>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let (path_tx, path_rx) = mpsc::channel();
+    let callback = move |request: &Request,
+                         response: Response|
+          -> Result<Response, ErrorResponse> {
+        match endpoint(request.uri().path()) {
+            Some(endpoint) => {
+                let _ = path_tx.send(endpoint);
+                Ok(response)
+            }
+            None => {
+                let mut error =
+                    ErrorResponse::new(Some(format!("Unknown endpoint {}", request.uri().path())));
+                *error.status_mut() = StatusCode::NOT_FOUND;
+                Err(error)
+            }
+        }
+    };
+    let mut socket = tungstenite::accept_hdr(stream, callback)?;
+    let endpoint = path_rx.recv()?;
+    socket.get_ref().set_nonblocking(true)?;
+
+    let (room, name) = match endpoint {
+        Endpoint::GameData { room, name } => (room, name),
+        Endpoint::Lobby => {
+            log::debug!("Lobby session {} opened", id);
+            let result = lobby_loop(&mut socket, &rooms, &running);
+            log::debug!("Lobby session {} closed", id);
+            return Ok(result?);
+        }
+    };
+
+    let (tx, rx) = mpsc::channel();
+    rooms
+        .lock()
+        .unwrap()
+        .entry(room.clone())
+        .or_default()
+        .insert(name.clone(), (id, tx));
+    log::info!("User {} joined room {}", name, room);
+    broadcast(
+        &rooms,
+        &room,
+        Message::Text(format!("User {} joined", name)),
+    );
+
+    let result = session_loop(&mut socket, &rx, &rooms, &room, &running);
+
+    let mut rooms_guard = rooms.lock().unwrap();
+    if let Some(sessions) = rooms_guard.get_mut(&room) {
+        if sessions.get(&name).map(|(session_id, _)| *session_id) == Some(id) {
+            sessions.remove(&name);
+        }
+        if sessions.is_empty() {
+            log::info!("Room {} closed", room);
+            rooms_guard.remove(&room);
+        }
+    }
+    drop(rooms_guard);
+
+    match result {
+        Ok(()) => log::info!("User {} left room {}", name, room),
+        Err(e) => {
+            log::info!("User {} left room {} on error: {}", name, room, e);
+            broadcast(
+                &rooms,
+                &room,
+                Message::Text(format!("User {} left on error: {}", name, e)),
+            );
+        }
+    }
+    Ok(())
+}
+
+#[allow(clippy::result_large_err)]
+fn session_loop(
+    socket: &mut WebSocket<TcpStream>,
+    rx: &Receiver<Message>,
+    rooms: &Rooms,
+    room: &str,
+    running: &AtomicBool,
+) -> tungstenite::Result<()> {
+    while running.load(Ordering::SeqCst) {
+        while let Ok(msg) = rx.try_recv() {
+            socket.write_message(msg)?;
+        }
+
+        match socket.read_message() {
+            Ok(msg) if msg.is_text() || msg.is_binary() => {
+                log::debug!("Received msg: {} bytes", msg.len());
+                broadcast(rooms, room, msg);
+            }
+            // Control frames are answered by tungstenite.
+            Ok(_) => (),
+            Err(tungstenite::Error::Io(e)) if e.kind() == io::ErrorKind::WouldBlock => {
+                sleep(Duration::from_millis(5));
+            }
+            Err(tungstenite::Error::ConnectionClosed) | Err(tungstenite::Error::AlreadyClosed) => {
+                return Ok(())
+            }
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+/// Rooms sorted by name with their players sorted by name.
+fn room_list(rooms: &Rooms) -> RoomList {
+    let mut rooms: Vec<RoomInfo> = rooms
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|(name, sessions)| {
+            let mut players: Vec<String> = sessions.keys().cloned().collect();
+            players.sort();
+            RoomInfo {
+                name: name.clone(),
+                players,
+            }
+        })
+        .collect();
+    rooms.sort_by(|room1, room2| room1.name.cmp(&room2.name));
+    RoomList { rooms }
+}
+
+/// Send the list of the rooms every time it changes, until the client leaves.
+#[allow(clippy::result_large_err)]
+fn lobby_loop(
+    socket: &mut WebSocket<TcpStream>,
+    rooms: &Rooms,
+    running: &AtomicBool,
+) -> tungstenite::Result<()> {
+    let mut sent = None;
+    let mut refresh_t = Instant::now();
+    while running.load(Ordering::SeqCst) {
+        if sent.is_none() || refresh_t.elapsed() > LOBBY_REFRESH {
+            let list = serde_json::to_string(&room_list(rooms)).unwrap();
+            if sent.as_ref() != Some(&list) {
+                socket.write_message(Message::Text(list.clone()))?;
+                sent = Some(list);
+            }
+            refresh_t = Instant::now();
+        }
+
+        match socket.read_message() {
+            // The lobby does not expect any message.
+            Ok(_) => (),
+            Err(tungstenite::Error::Io(e)) if e.kind() == io::ErrorKind::WouldBlock => {
+                sleep(Duration::from_millis(20));
+            }
+            Err(tungstenite::Error::ConnectionClosed) | Err(tungstenite::Error::AlreadyClosed) => {
+                return Ok(())
+            }
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+fn broadcast(rooms: &Rooms, room: &str, msg: Message) {
+    if let Some(sessions) = rooms.lock().unwrap().get(room) {
+        for (_id, tx) in sessions.values() {
+            // A session being closed does not receive messages anymore.
+            let _ = tx.send(msg.clone());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tungstenite::client::IntoClientRequest;
+    use tungstenite::stream::MaybeTlsStream;
+
+    type Client = WebSocket<MaybeTlsStream<TcpStream>>;
+
+    fn connect(addr: SocketAddr, name: &str) -> Client {
+        let (socket, _response) =
+            tungstenite::connect(format!("ws://{}/gamedata/{}", addr, name)).unwrap();
+        socket
+    }
+
+    fn read_text(socket: &mut Client) -> String {
+        loop {
+            match socket.read_message().unwrap() {
+                Message::Text(text) => return text,
+                Message::Binary(_) => panic!("Unexpected binary message"),
+                _ => (),
+            }
+        }
+    }
+
+    #[test]
+    fn room_and_username_test() {
+        let some = |room: &str, name: &str| Some((String::from(room), String::from(name)));
+        assert_eq!(
+            room_and_username("/gamedata/Uggla"),
+            some(DEFAULT_ROOM, "Uggla")
+        );
+        assert_eq!(
+            room_and_username("/gamedata/Planetoid%20guest"),
+            some(DEFAULT_ROOM, "Planetoid guest")
+        );
+        assert_eq!(
+            room_and_username("/gamedata/game%201/Uggla"),
+            some("game 1", "Uggla")
+        );
+        assert_eq!(room_and_username("/gamedata/"), None);
+        assert_eq!(room_and_username("/gamedata//Uggla"), None);
+        assert_eq!(room_and_username("/gamedata/a/b/c"), None);
+        assert_eq!(room_and_username("/chat/Uggla"), None);
+    }
+
+    #[test]
+    fn relay_broadcast_test() {
+        let relay = Relay::bind("127.0.0.1:0").unwrap();
+        let addr = relay.local_addr().unwrap();
+        let handle = relay.spawn();
+
+        let mut host = connect(addr, "host");
+        assert_eq!(read_text(&mut host), "User host joined");
+        let mut guest = connect(addr, "Planetoid%20guest");
+        assert_eq!(read_text(&mut host), "User Planetoid guest joined");
+        assert_eq!(read_text(&mut guest), "User Planetoid guest joined");
+
+        // Messages are sent to every session including the sender.
+        host.write_message(Message::Text(String::from("hello world")))
+            .unwrap();
+        assert_eq!(read_text(&mut host), "hello world");
+        assert_eq!(read_text(&mut guest), "hello world");
+
+        guest.write_message(Message::Binary(vec![1, 2, 3])).unwrap();
+        for socket in [&mut host, &mut guest] {
+            loop {
+                match socket.read_message().unwrap() {
+                    Message::Binary(data) => {
+                        assert_eq!(data, vec![1, 2, 3]);
+                        break;
+                    }
+                    Message::Text(text) => panic!("Unexpected message {}", text),
+                    _ => (),
+                }
+            }
+        }
+
+        guest.close(None).unwrap();
+        while guest.read_message().is_ok() {}
+        host.write_message(Message::Text(String::from("still there")))
+            .unwrap();
+        assert_eq!(read_text(&mut host), "still there");
+
+        handle.stop().unwrap();
+    }
+
+    #[test]
+    fn relay_rooms_test() {
+        let relay = Relay::bind("127.0.0.1:0").unwrap();
+        let addr = relay.local_addr().unwrap();
+        let handle = relay.spawn();
+
+        let mut host1 = connect(addr, "game1/host");
+        assert_eq!(read_text(&mut host1), "User host joined");
+        let mut host2 = connect(addr, "game2/host");
+        assert_eq!(read_text(&mut host2), "User host joined");
+        let mut guest1 = connect(addr, "game1/guest");
+        assert_eq!(read_text(&mut guest1), "User guest joined");
+        assert_eq!(read_text(&mut host1), "User guest joined");
+        let mut worker_host = connect(addr, "host");
+        assert_eq!(read_text(&mut worker_host), "User host joined");
+
+        // Each room only receives its own messages.
+        host2
+            .write_message(Message::Text(String::from("game2")))
+            .unwrap();
+        assert_eq!(read_text(&mut host2), "game2");
+        host1
+            .write_message(Message::Text(String::from("game1")))
+            .unwrap();
+        assert_eq!(read_text(&mut host1), "game1");
+        assert_eq!(read_text(&mut guest1), "game1");
+        worker_host
+            .write_message(Message::Text(String::from("default")))
+            .unwrap();
+        assert_eq!(read_text(&mut worker_host), "default");
+        host2
+            .write_message(Message::Text(String::from("game2 again")))
+            .unwrap();
+        assert_eq!(read_text(&mut host2), "game2 again");
+
+        handle.stop().unwrap();
+    }
+
+    #[test]
+    fn endpoint_test() {
+        assert_eq!(endpoint("/lobby"), Some(Endpoint::Lobby));
+        assert_eq!(
+            endpoint("/gamedata/game1/Uggla"),
+            Some(Endpoint::GameData {
+                room: String::from("game1"),
+                name: String::from("Uggla")
+            })
+        );
+        assert_eq!(endpoint("/lobby/game1"), None);
+    }
+
+    #[test]
+    fn relay_lobby_test() {
+        let relay = Relay::bind("127.0.0.1:0").unwrap();
+        let addr = relay.local_addr().unwrap();
+        let handle = relay.spawn();
+
+        let (mut lobby, _response) = tungstenite::connect(format!("ws://{}/lobby", addr)).unwrap();
+        assert_eq!(read_text(&mut lobby), r#"{"rooms":[]}"#);
+
+        let mut host = connect(addr, "game1/host");
+        assert_eq!(read_text(&mut host), "User host joined");
+        assert_eq!(
+            read_text(&mut lobby),
+            r#"{"rooms":[{"name":"game1","players":["host"]}]}"#
+        );
+        let mut guest = connect(addr, "game1/guest");
+        assert_eq!(read_text(&mut guest), "User guest joined");
+        assert_eq!(
+            read_text(&mut lobby),
+            r#"{"rooms":[{"name":"game1","players":["guest","host"]}]}"#
+        );
+
+        // Rooms are closed when their last player leaves.
+        for mut socket in [host, guest] {
+            socket.close(None).unwrap();
+            while socket.read_message().is_ok() {}
+        }
+        loop {
+            if read_text(&mut lobby) == r#"{"rooms":[]}"# {
+                break;
+            }
+        }
+
+        handle.stop().unwrap();
+    }
+
+    #[test]
+    fn relay_unknown_endpoint_test() {
+        let relay = Relay::bind("127.0.0.1:0").unwrap();
+        let addr = relay.local_addr().unwrap();
+        let handle = relay.spawn();
+
+        let request = format!("ws://{}/chat/Uggla", addr)
+            .into_client_request()
+            .unwrap();
+        match tungstenite::connect(request) {
+            Err(tungstenite::Error::Http(response)) => {
+                assert_eq!(response.status(), StatusCode::NOT_FOUND)
+            }
+            result => panic!("Unexpected result {:?}", result.map(|_| ())),
+        }
+
+        handle.stop().unwrap();
+    }
+
+    #[test]
+    fn relay_stop_closes_sessions_test() {
+        let relay = Relay::bind("127.0.0.1:0").unwrap();
+        let addr = relay.local_addr().unwrap();
+        let handle = relay.spawn();
+
+        let mut host = connect(addr, "host");
+        assert_eq!(read_text(&mut host), "User host joined");
+        handle.stop().unwrap();
+        // Reading fails once the relay dropped the session.
+        while host.read_message().is_ok() {}
+    }
+}
@@ -0,0 +1,57 @@
+use planetoid_relay::Relay;
+use simple_logger::SimpleLogger;
+use structopt::clap::{crate_name, crate_version};
+use structopt::StructOpt;
+
+#[derive(Debug, StructOpt)]
+#[structopt(
+    name = crate_name!(),
+    version = crate_version!(),
+    about = "Planetoid relay, broadcast game data between players."
+)]
+struct Opt {
+    /// Debug mode (ϕ (error), -d (info), -dd (debug), -ddd (trace))
+    #[structopt(short, long, parse(from_occurrences))]
+    debug: u8,
+
+    /// Address to listen on
+    #[structopt(short, long, default_value = "0.0.0.0")]
+    bind: String,
+
+    /// Port
+    #[structopt(short, long, default_value = "8080")]
+    port: u16,
+}
+
+fn get_log_level(debug_occurence: u8) -> log::LevelFilter {
+    match debug_occurence {
+        0 => log::LevelFilter::Error,
+        1 => log::LevelFilter::Info,
+        2 => log::LevelFilter::Debug,
+        3 => log::LevelFilter::Trace,
+        _ => log::LevelFilter::Error,
+    }
+}
+
+fn main() {
+    let opt = Opt::from_args();
+
+    SimpleLogger::new()
+        .with_utc_timestamps()
+        .with_level(get_log_level(opt.debug))
+        .init()
+        .unwrap();
+    log::debug!("{:#?}", opt);
+
+    let relay = match Relay::bind((opt.bind.as_str(), opt.port)) {
+        Ok(relay) => relay,
+        Err(e) => {
+            log::error!("Cannot listen on {}:{}: {}", opt.bind, opt.port, e);
+            std::process::exit(1);
+        }
+    };
+    if let Err(e) = relay.run() {
+        log::error!("{}", e);
+        std::process::exit(1);
+    }
+}
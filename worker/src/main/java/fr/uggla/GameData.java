@@ -1,7 +1,6 @@
 package fr.uggla;
 
 import java.nio.ByteBuffer;
-import java.nio.charset.StandardCharsets;
 import java.util.Map;
 import java.util.concurrent.ConcurrentHashMap;
 
@@ -37,13 +36,12 @@ public class GameData {
         broadcast("User " + username + " left on error: " + throwable);
     }
 
-// Example using binary message
-//    @OnMessage
-//    public void onMessage(ByteBuffer message, @PathParam("username") String username) {
-//        String msg = StandardCharsets.UTF_8.decode(message).toString();
-//        System.out.println("Received msg:" + msg );
-//        broadcast(">> " + username + ": " + msg);
-//     }
+    @OnMessage
+    public void onMessage(ByteBuffer message, @PathParam("username") String username) {
+        System.out.println("Received binary msg: " + message.remaining() + " bytes");
+        broadcast(message);
+    }
+
     @OnMessage
     public void onMessage(String message, @PathParam("username") String username) {
         System.out.println("Received msg:" + message);
@@ -51,6 +49,16 @@ public class GameData {
         broadcast(message);
     }
 
+    private void broadcast(ByteBuffer message) {
+        sessions.values().forEach(s -> {
+            s.getAsyncRemote().sendBinary(message.duplicate(), result -> {
+                if (result.getException() != null) {
+                    System.out.println("Unable to send message: " + result.getException());
+                }
+            });
+        });
+    }
+
     private void broadcast(String message) {
         sessions.values().forEach(s -> {
             s.getAsyncRemote().sendObject(message, result -> {